@@ -1,24 +1,41 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Signed, ToPrimitive, Zero};
+
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use crate::reader::{read_file, read_str};
+use crate::types::MalErr::{ErrMalVal, ErrString, ErrTyped};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio,
+    Reduced, Regex, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    _assoc, _conj_set, _disj, _dissoc, atom, error, func, hash_map, hash_set, int_val, keyword,
+    lazy_cons, lazy_seq, ratio, type_error, MalArgs, MalErr, MalFn, MalList, MalMap, MalRet,
+    MalSet, MalVal, MalVec, Symbol,
+};
 
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+macro_rules! fn_round {
+    ($fn_f:expr, $fn_r:expr) => {{
+        |a: MalArgs| match a[0] {
+            Int(_) | Big(_) => Ok(a[0].clone()),
+            Ratio(ref r) => Ok(int_val($fn_r(&**r).to_integer())),
+            Float(f) => Ok(Float($fn_f(f))),
+            _ => type_error("expecting (number) arg"),
         }
     }};
 }
@@ -39,15 +56,19 @@ macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| match a[0].clone() {
             Str(a0) => $fn(a0),
-            _ => error("expecting (str) arg"),
+            _ => type_error("expecting (str) arg"),
         }
     }};
 }
 
+fn realize_printed(a: &MalArgs) -> Result<(), MalErr> {
+    a.iter().try_for_each(|x| x.realize_printed())
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
-        _ => error("illegal symbol call"),
+        Str(ref s) => Ok(Sym(Symbol::new(s))),
+        _ => type_error("illegal symbol call"),
     }
 }
 
@@ -75,18 +96,66 @@ fn readline(a: MalArgs) -> MalRet {
                 Err(e) => error(&format!("{:?}", e)),
             }
         }
-        _ => error("readline: prompt is not Str"),
+        _ => type_error("readline: prompt is not Str"),
     }
 }
 
-fn slurp(f: String) -> MalRet {
+fn read_text(f: &str) -> Result<String, MalErr> {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
-        Ok(_) => Ok(Str(s)),
-        Err(e) => error(&e.to_string()),
+        Ok(_) => Ok(s),
+        Err(e) => Err(ErrString(e.to_string())),
+    }
+}
+
+fn slurp(f: String) -> MalRet {
+    Ok(Str(read_text(&f)?))
+}
+
+// The forms of a file wrapped in a do block, positioned for error reports
+fn read_file_forms(f: String) -> MalRet {
+    let mut forms = vec![Sym(Symbol::new("do"))];
+    forms.extend(read_file(read_text(&f)?, &f)?);
+    forms.push(Nil);
+    Ok(list!(forms))
+}
+
+// (keyword name) or (keyword ns name)
+fn make_keyword(a: MalArgs) -> MalRet {
+    match &a[..] {
+        [k] => k.keyword(),
+        [Nil, Str(n)] => Ok(keyword(n)),
+        [Str(ns), Str(n)] => Ok(keyword(&format!("{}/{}", ns, n))),
+        _ => type_error("keyword: expecting string args"),
+    }
+}
+
+// :ns/kw and ns/sym split at the first '/', a lone "/" has no namespace
+fn split_ns(s: &str) -> (Option<&str>, &str) {
+    match s.find('/') {
+        Some(i) if i > 0 && i + 1 < s.len() => (Some(&s[..i]), &s[i + 1..]),
+        _ => (None, s),
     }
 }
 
+fn name(a: MalArgs) -> MalRet {
+    match a[0] {
+        Keyword(ref k) => Ok(Str(split_ns(k).1.to_string())),
+        Sym(ref s) => Ok(Str(split_ns(s).1.to_string())),
+        Str(ref s) => Ok(Str(s.to_string())),
+        _ => type_error("name: expecting a keyword, symbol or string"),
+    }
+}
+
+fn namespace(a: MalArgs) -> MalRet {
+    let ns = match a[0] {
+        Keyword(ref k) => split_ns(k).0,
+        Sym(ref s) => split_ns(s).0,
+        _ => return type_error("namespace: expecting a keyword or symbol"),
+    };
+    Ok(ns.map_or(Nil, |ns| Str(ns.to_string())))
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -97,255 +166,1957 @@ fn time_ms(_a: MalArgs) -> MalRet {
     ))
 }
 
+// Binary numeric operation over the tower Int < Big < Ratio < Float.
+// Int results that overflow are recomputed with the Big variant.
+struct NumOp {
+    int: fn(i64, i64) -> Option<i64>,
+    big: fn(&BigInt, &BigInt) -> MalVal,
+    ratio: fn(&BigRational, &BigRational) -> MalVal,
+    float: fn(f64, f64) -> f64,
+}
+
+const ADD: NumOp = NumOp {
+    int: i64::checked_add,
+    big: |x, y| Big(Rc::new(x + y)),
+    ratio: |x, y| ratio(x + y),
+    float: |x, y| x + y,
+};
+
+const SUB: NumOp = NumOp {
+    int: i64::checked_sub,
+    big: |x, y| Big(Rc::new(x - y)),
+    ratio: |x, y| ratio(x - y),
+    float: |x, y| x - y,
+};
+
+const MUL: NumOp = NumOp {
+    int: i64::checked_mul,
+    big: |x, y| Big(Rc::new(x * y)),
+    ratio: |x, y| ratio(x * y),
+    float: |x, y| x * y,
+};
+
+const DIV: NumOp = NumOp {
+    int: |x, y| match x.checked_rem(y) {
+        Some(0) => x.checked_div(y),
+        _ => None,
+    },
+    big: |x, y| ratio(BigRational::new(x.clone(), y.clone())),
+    ratio: |x, y| ratio(x / y),
+    float: |x, y| x / y,
+};
+
+const QUOT: NumOp = NumOp {
+    int: i64::checked_div,
+    big: |x, y| Big(Rc::new(x / y)),
+    ratio: |x, y| int_val((x / y).trunc().to_integer()),
+    float: |x, y| (x / y).trunc(),
+};
+
+const REM: NumOp = NumOp {
+    // i64::MIN % -1 is mathematically 0, so wrapping is exact here
+    int: |x, y| Some(x.wrapping_rem(y)),
+    big: |x, y| Big(Rc::new(x % y)),
+    ratio: |x, y| ratio(x - (x / y).trunc() * y),
+    float: |x, y| x % y,
+};
+
+const MOD: NumOp = NumOp {
+    int: |x, y| match x.wrapping_rem(y) {
+        r if r != 0 && (r < 0) != (y < 0) => Some(r + y),
+        r => Some(r),
+    },
+    big: |x, y| Big(Rc::new(x.mod_floor(y))),
+    ratio: |x, y| ratio(x - (x / y).floor() * y),
+    float: |x, y| match x % y {
+        r if r != 0.0 && (r < 0.0) != (y < 0.0) => r + y,
+        r => r,
+    },
+};
+
+fn to_big(a: &MalVal) -> Option<BigInt> {
+    match a {
+        Int(i) => Some(BigInt::from(*i)),
+        Big(b) => Some((**b).clone()),
+        _ => None,
+    }
+}
+
+fn to_ratio(a: &MalVal) -> Option<BigRational> {
+    match a {
+        Ratio(r) => Some((**r).clone()),
+        _ => to_big(a).map(BigRational::from_integer),
+    }
+}
+
+fn to_f64(a: &MalVal) -> Option<f64> {
+    match a {
+        Int(i) => Some(*i as f64),
+        Float(f) => Some(*f),
+        Big(b) => b.to_f64(),
+        Ratio(r) => r.to_f64(),
+        _ => None,
+    }
+}
+
+fn num_op(name: &str, op: &NumOp, a: &MalVal, b: &MalVal) -> MalRet {
+    let type_err = || type_error(&format!("{}: expecting number args", name));
+    match (a, b) {
+        (Int(x), Int(y)) => match (op.int)(*x, *y) {
+            Some(r) => Ok(Int(r)),
+            None => Ok((op.big)(&BigInt::from(*x), &BigInt::from(*y))),
+        },
+        (Float(_), _) | (_, Float(_)) => match (to_f64(a), to_f64(b)) {
+            (Some(x), Some(y)) => Ok(Float((op.float)(x, y))),
+            _ => type_err(),
+        },
+        (Ratio(_), _) | (_, Ratio(_)) => match (to_ratio(a), to_ratio(b)) {
+            (Some(x), Some(y)) => Ok((op.ratio)(&x, &y)),
+            _ => type_err(),
+        },
+        _ => match (to_big(a), to_big(b)) {
+            (Some(x), Some(y)) => Ok((op.big)(&x, &y)),
+            _ => type_err(),
+        },
+    }
+}
+
+// Only exact division by zero is an error, floats follow IEEE 754
+fn num_div_op(name: &str, op: &NumOp, a: &MalVal, b: &MalVal) -> MalRet {
+    let zero_div = || Err(ErrTyped("arithmetic", "Divide by zero".to_string()));
+    match (a, b) {
+        (Float(_), _) => num_op(name, op, a, b),
+        (_, Int(0)) => zero_div(),
+        (_, Big(y)) if y.is_zero() => zero_div(),
+        _ => num_op(name, op, a, b),
+    }
+}
+
+fn num_cmp(a: &MalVal, b: &MalVal) -> Result<Option<Ordering>, MalErr> {
+    let type_err = || Err(ErrString("expecting number args".to_string()));
+    match (a, b) {
+        (Int(x), Int(y)) => Ok(Some(x.cmp(y))),
+        (Float(_), _) | (_, Float(_)) => match (to_f64(a), to_f64(b)) {
+            (Some(x), Some(y)) => Ok(x.partial_cmp(&y)),
+            _ => type_err(),
+        },
+        _ => match (to_ratio(a), to_ratio(b)) {
+            (Some(x), Some(y)) => Ok(Some(x.cmp(&y))),
+            _ => type_err(),
+        },
+    }
+}
+
+fn add(a: MalArgs) -> MalRet {
+    let mut acc = Int(0);
+    for x in a.iter() {
+        acc = num_op("+", &ADD, &acc, x)?;
+    }
+    Ok(acc)
+}
+
+fn mul(a: MalArgs) -> MalRet {
+    let mut acc = Int(1);
+    for x in a.iter() {
+        acc = num_op("*", &MUL, &acc, x)?;
+    }
+    Ok(acc)
+}
+
+fn sub(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => num_op("-", &SUB, &Int(0), &a[0]),
+        _ => {
+            let mut acc = a[0].clone();
+            for x in a[1..].iter() {
+                acc = num_op("-", &SUB, &acc, x)?;
+            }
+            Ok(acc)
+        }
+    }
+}
+
+fn div(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => num_div_op("/", &DIV, &Int(1), &a[0]),
+        _ => {
+            let mut acc = a[0].clone();
+            for x in a[1..].iter() {
+                acc = num_div_op("/", &DIV, &acc, x)?;
+            }
+            Ok(acc)
+        }
+    }
+}
+
+fn abs(a: MalArgs) -> MalRet {
+    match a[0] {
+        Int(i) => match i.checked_abs() {
+            Some(r) => Ok(Int(r)),
+            None => Ok(Big(Rc::new(BigInt::from(i).abs()))),
+        },
+        Big(ref b) => Ok(Big(Rc::new(b.abs()))),
+        Ratio(ref r) => Ok(Ratio(Rc::new(r.abs()))),
+        Float(f) => Ok(Float(f.abs())),
+        _ => type_error("abs: expecting number"),
+    }
+}
+
+fn compare(name: &str, a: MalArgs, ok: fn(Ordering) -> bool) -> MalRet {
+    for w in a.windows(2) {
+        match num_cmp(&w[0], &w[1]) {
+            Ok(Some(o)) if ok(o) => (),
+            Ok(_) => return Ok(Bool(false)),
+            Err(_) => return type_error(&format!("{}: expecting number args", name)),
+        }
+    }
+    Ok(Bool(true))
+}
+
+fn extremum(name: &str, a: MalArgs, keep: Ordering) -> MalRet {
+    let mut acc = a[0].clone();
+    for x in a[1..].iter() {
+        match num_cmp(x, &acc) {
+            Ok(Some(o)) if o == keep => acc = x.clone(),
+            Ok(_) => (),
+            Err(_) => return type_error(&format!("{}: expecting number args", name)),
+        }
+    }
+    Ok(acc)
+}
+
+fn equal(a: MalArgs) -> MalRet {
+    Ok(Bool(a.windows(2).all(|w| w[0] == w[1])))
+}
+
+fn char(a: MalArgs) -> MalRet {
+    let code = match a[0] {
+        Char(_) => return Ok(a[0].clone()),
+        Int(i) => i.to_u32().and_then(std::char::from_u32),
+        _ => return type_error("char: expecting an integer"),
+    };
+    match code {
+        Some(c) => Ok(Char(c)),
+        None => error(&format!(
+            "char: {} is not a Unicode scalar value",
+            a[0].pr_str(true)
+        )),
+    }
+}
+
+fn double(a: MalArgs) -> MalRet {
+    match to_f64(&a[0]) {
+        Some(f) => Ok(Float(f)),
+        None => type_error("double: expecting number"),
+    }
+}
+
+fn int(a: MalArgs) -> MalRet {
+    let out_of_range = || error(&format!("int: {} out of range", a[0].pr_str(true)));
+    match a[0] {
+        Int(i) => Ok(Int(i)),
+        // i64::MAX as f64 rounds up to 2^63, hence the strict bound
+        Float(f) if f >= i64::MIN as f64 && f < i64::MAX as f64 => Ok(Int(f as i64)),
+        Float(_) => out_of_range(),
+        Big(ref b) => match b.to_i64() {
+            Some(i) => Ok(Int(i)),
+            None => out_of_range(),
+        },
+        Ratio(ref r) => match r.trunc().to_integer().to_i64() {
+            Some(i) => Ok(Int(i)),
+            None => out_of_range(),
+        },
+        Char(c) => Ok(Int(c as i64)),
+        _ => type_error("int: expecting number"),
+    }
+}
+
+fn bigint(a: MalArgs) -> MalRet {
+    match a[0] {
+        Float(f) => match BigInt::from_f64(f.trunc()) {
+            Some(b) => Ok(Big(Rc::new(b))),
+            None => error(&format!("bigint: {} out of range", a[0].pr_str(true))),
+        },
+        Ratio(ref r) => Ok(Big(Rc::new(r.trunc().to_integer()))),
+        _ => match to_big(&a[0]) {
+            Some(b) => Ok(Big(Rc::new(b))),
+            None => type_error("bigint: expecting number"),
+        },
+    }
+}
+
+fn numerator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(int_val(r.numer().clone())),
+        Int(_) | Big(_) => Ok(a[0].clone()),
+        _ => type_error("numerator: expecting rational"),
+    }
+}
+
+fn denominator(a: MalArgs) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(int_val(r.denom().clone())),
+        Int(_) | Big(_) => Ok(Int(1)),
+        _ => type_error("denominator: expecting rational"),
+    }
+}
+
 fn get(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
         (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+        (Hash(ref hm, _), ref k) => match hm.get(k) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
-        _ => error("illegal get args"),
+        (Set(ref s, _), ref k) if s.contains(k) => Ok(k.clone()),
+        (Set(..), _) => Ok(Nil),
+        _ => type_error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("assoc on non-Hash Map"),
+        Hash(ref hm, _) => _assoc(hm.clone(), a[1..].to_vec()),
+        _ => type_error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
-        _ => error("dissoc on non-Hash Map"),
+        Hash(ref hm, _) => _dissoc(hm.clone(), a[1..].to_vec()),
+        _ => type_error("dissoc on non-Hash Map"),
     }
 }
 
+fn set(a: MalArgs) -> MalRet {
+    match a[0] {
+        Nil => hash_set(vec![]),
+        List(..) | Vector(..) => hash_set(a[0].elems().unwrap().cloned().collect()),
+        Hash(ref hm, _) => hash_set(
+            hm.iter()
+                .map(|(k, v)| vector![k.clone(), v.clone()])
+                .collect(),
+        ),
+        Set(ref s, _) => Ok(Set(s.clone(), Rc::new(Nil))),
+        _ => type_error("set called with non-seq"),
+    }
+}
+
+fn disj(a: MalArgs) -> MalRet {
+    match a[0] {
+        Set(ref s, _) => _disj(s.clone(), a[1..].to_vec()),
+        Nil => Ok(Nil),
+        _ => type_error("disj on non-Set"),
+    }
+}
+
+fn set_args(name: &str, a: MalArgs) -> Result<Vec<MalSet>, MalErr> {
+    a.into_iter()
+        .map(|x| match x {
+            Set(s, _) => Ok(s),
+            _ => Err(ErrTyped("wrong-type", format!("{} expects sets", name))),
+        })
+        .collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+    let s = MalSet::unions(set_args("union", a)?);
+    Ok(Set(s, Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+    let mut sets = set_args("intersection", a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(sets.fold(first, MalSet::intersection), Rc::new(Nil)))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+    let mut sets = set_args("difference", a)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(Set(
+        sets.fold(first, MalSet::relative_complement),
+        Rc::new(Nil),
+    ))
+}
+
+fn subset_q(a: MalArgs) -> MalRet {
+    let sets = set_args("subset?", a)?;
+    Ok(Bool(sets[0].is_subset(&sets[1])))
+}
+
+fn superset_q(a: MalArgs) -> MalRet {
+    let sets = set_args("superset?", a)?;
+    Ok(Bool(sets[1].is_subset(&sets[0])))
+}
+
 fn contains_q(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
-        _ => error("illegal get args"),
+        (Hash(ref hm, _), ref k) => Ok(Bool(hm.contains_key(k))),
+        (Set(ref s, _), ref k) => Ok(Bool(s.contains(k))),
+        _ => type_error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect::<MalList>())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
-        _ => error("keys requires Hash Map"),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect::<MalList>())),
+        _ => type_error("keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
-    match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
-    }
+    Ok(vector!(seq_arg("vec", &a[0])?))
 }
 
 fn cons(a: MalArgs) -> MalRet {
-    match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
-        }
-        _ => error("cons expects seq as second arg"),
+    match a[1] {
+        List(ref l, _) => Ok(list!(l.cons(a[0].clone()))),
+        Vector(ref v, _) => Ok(list!(MalList::from((**v).clone()).cons(a[0].clone()))),
+        LazySeq(_) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
+        _ => type_error("cons expects seq as second arg"),
     }
 }
 
+// Lazy when any of its seqs is, a list otherwise, as quasiquote needs
 fn concat(a: MalArgs) -> MalRet {
-    let mut new_v = vec![];
-    for seq in a.iter() {
+    if a.iter().any(is_lazy) {
+        let seqs = a
+            .iter()
+            .map(|s| seq_of("concat", s))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(lazy_cat(list![], list!(seqs)));
+    }
+    // a trailing list is shared as the tail of the result, not copied
+    let mut elems = vec![];
+    let mut tail = MalList::new();
+    for (i, seq) in a.iter().enumerate() {
         match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+            List(l, _) if i == a.len() - 1 => tail = l.clone(),
+            List(..) | Vector(..) => elems.extend(seq.elems().unwrap().cloned()),
+            _ => return type_error("non-seq passed to concat"),
         }
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(list!(tail.prepend(elems.into_iter())))
 }
 
 fn nth(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
-            if seq.len() <= idx as usize {
-                return error("nth: index out of range");
+        (ref seq @ List(..), Int(idx)) | (ref seq @ Vector(..), Int(idx)) => {
+            match seq.elems().unwrap().nth(idx as usize) {
+                Some(x) if idx >= 0 => Ok(x.clone()),
+                _ => error("nth: index out of range"),
             }
-            Ok(seq[idx as usize].clone())
         }
-        _ => error("invalid args to nth"),
+        (Str(s), Int(idx)) => match s.chars().nth(idx as usize) {
+            Some(c) if idx >= 0 => Ok(Char(c)),
+            _ => error("nth: index out of range"),
+        },
+        (s @ LazySeq(_), Int(idx)) if idx >= 0 => {
+            let mut s = s;
+            for _ in 0..idx {
+                match s.uncons()? {
+                    Some((_, r)) => s = r,
+                    None => return error("nth: index out of range"),
+                }
+            }
+            match s.uncons()? {
+                Some((x, _)) => Ok(x),
+                None => error("nth: index out of range"),
+            }
+        }
+        _ => type_error("invalid args to nth"),
     }
 }
 
 fn first(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
+        List(ref l, _) => Ok(l.head().cloned().unwrap_or(Nil)),
+        Vector(ref v, _) => Ok(v.front().cloned().unwrap_or(Nil)),
+        Str(ref s) => Ok(s.chars().next().map_or(Nil, Char)),
+        ref s @ LazySeq(_) => Ok(s.uncons()?.map_or(Nil, |(x, _)| x)),
         Nil => Ok(Nil),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
     match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
-        }
+        List(ref l, _) => Ok(list!(l.skip(1))),
+        Vector(ref v, _) if v.len() > 1 => Ok(list!(v.skip(1))),
+        Vector(..) => Ok(list![]),
+        ref s @ LazySeq(_) => match s.uncons()? {
+            Some((_, r)) => Ok(r),
+            None => Ok(list![]),
+        },
         Nil => Ok(list![]),
-        _ => error("invalid args to first"),
+        _ => type_error("invalid args to first"),
     }
 }
 
 fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let f = &a[0];
-            let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
-            f.apply(fargs)
+    let v = match a[a.len() - 1] {
+        ref s @ LazySeq(_) => s.realize()?,
+        ref s => match s.elems() {
+            Some(xs) => xs.cloned().collect(),
+            None => return type_error("apply called with non-seq"),
+        },
+    };
+    let mut fargs = a[1..a.len() - 1].to_vec();
+    fargs.extend(v);
+    a[0].apply(fargs)
+}
+
+fn conj(a: MalArgs) -> MalRet {
+    if a.is_empty() {
+        return Ok(vector![]);
+    }
+    match a[0] {
+        List(ref l, _) => Ok(list!(a[1..]
+            .iter()
+            .fold(l.clone(), |l, x| l.cons(x.clone())))),
+        Vector(ref v, _) => {
+            let mut v = (**v).clone();
+            v.extend(a[1..].iter().cloned());
+            Ok(vector!(v))
         }
-        _ => error("apply called with non-seq"),
+        Set(ref s, _) => _conj_set(s.clone(), a[1..].to_vec()),
+        Hash(ref hm, _) => {
+            let mut kvs = vec![];
+            for x in a[1..].iter() {
+                match x {
+                    Vector(kv, _) if kv.len() == 2 => kvs.extend(kv.iter().cloned()),
+                    Hash(m, _) => {
+                        kvs.extend(m.iter().flat_map(|(k, v)| vec![k.clone(), v.clone()]))
+                    }
+                    _ => return type_error("conj: a hash-map takes [key value] vectors or maps"),
+                }
+            }
+            _assoc(hm.clone(), kvs)
+        }
+        Nil => Ok(list!(a[1..].iter().rev().cloned().collect::<MalList>())),
+        LazySeq(_) => Ok(a[1..]
+            .iter()
+            .fold(a[0].clone(), |s, x| lazy_cons(x.clone(), s))),
+        _ => type_error("conj: called with non-seq"),
+    }
+}
+
+fn seq(a: MalArgs) -> MalRet {
+    match a[0] {
+        List(ref l, _) if l.is_empty() => Ok(Nil),
+        List(ref l, _) => Ok(list!(l.clone())),
+        Vector(ref v, _) if v.is_empty() => Ok(Nil),
+        Vector(ref v, _) => Ok(list!((**v).clone())),
+        Set(ref s, _) if s.is_empty() => Ok(Nil),
+        Set(ref s, _) => Ok(list!(s.iter().cloned().collect::<MalList>())),
+        Str(ref s) if s.len() == 0 => Ok(Nil),
+        Str(ref s) => Ok(list!(s.chars().map(Char).collect::<MalList>())),
+        ref s @ LazySeq(_) => Ok(s.uncons()?.map_or(Nil, |_| s.clone())),
+        Nil => Ok(Nil),
+        _ => type_error("seq: called with non-seq"),
+    }
+}
+
+// The elements of each seq in seqs in turn, cur's first. seqs is itself
+// a seq, and neither is realized further than the elements taken.
+fn lazy_cat(cur: MalVal, seqs: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let (mut cur, mut seqs) = (cur.clone(), seqs.clone());
+        loop {
+            if let Some((x, r)) = cur.uncons()? {
+                return Ok(lazy_cons(x, lazy_cat(r, seqs)));
+            }
+            match seqs.uncons()? {
+                Some((s, r)) => {
+                    cur = seq_of("concat", &s)?;
+                    seqs = r;
+                }
+                None => return Ok(Nil),
+            }
+        }
+    })
+}
+
+// The elements of anything seq accepts, realizing lazy seqs in full
+fn seq_arg(name: &str, a: &MalVal) -> Result<MalVec, MalErr> {
+    match a {
+        LazySeq(_) => a.realize(),
+        _ => a
+            .seq_elems()
+            .ok_or_else(|| ErrTyped("wrong-type", format!("{}: called with non-seq", name))),
+    }
+}
+
+// Anything seq accepts as a seq that is cheap to take apart: lazy seqs
+// and lists as they are, the rest copied into a list
+fn seq_of(name: &str, a: &MalVal) -> MalRet {
+    match a {
+        LazySeq(_) | List(..) => Ok(a.clone()),
+        _ => seq_arg(name, a).map(|l| list!(l)),
+    }
+}
+
+fn count_arg(name: &str, a: &MalVal) -> Result<usize, MalErr> {
+    match a {
+        Int(n) => Ok(if *n < 0 { 0 } else { *n as usize }),
+        _ => Err(ErrTyped(
+            "wrong-type",
+            format!("{}: expecting an integer count", name),
+        )),
     }
 }
 
+fn truthy(a: &MalVal) -> bool {
+    !matches!(a, Nil | Bool(false))
+}
+
+fn is_lazy(a: &MalVal) -> bool {
+    matches!(a, LazySeq(_))
+}
+
+// map, filter, take, drop, mapcat, interleave and partition(-all) return
+// lazy seqs whatever they are given; their arguments are checked up front
+
 fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
-            let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+    if a.len() == 1 {
+        return Ok(map_xf(a[0].clone()));
+    }
+    let seqs = a[1..]
+        .iter()
+        .map(|s| seq_of("map", s))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(lazy_map(a[0].clone(), seqs))
+}
+
+fn lazy_map(f: MalVal, seqs: Vec<MalVal>) -> MalVal {
+    lazy_seq(move || {
+        let mut args = vec![];
+        let mut rests = vec![];
+        for s in seqs.iter() {
+            match s.uncons()? {
+                Some((x, r)) => {
+                    args.push(x);
+                    rests.push(r);
+                }
+                None => return Ok(Nil),
             }
-            Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
+        Ok(lazy_cons(f.apply(args)?, lazy_map(f.clone(), rests)))
+    })
+}
+
+fn mapcat(a: MalArgs) -> MalRet {
+    Ok(lazy_cat(list![], map(a)?))
+}
+
+// Fold coll with f, stopping early when f returns a (reduced x). Lazy
+// seqs are only realized as far as the fold gets.
+fn fold(
+    name: &str,
+    coll: &MalVal,
+    init: MalVal,
+    mut f: impl FnMut(MalVal, MalVal) -> MalRet,
+) -> MalRet {
+    let mut acc = init;
+    if is_lazy(coll) {
+        let mut s = coll.clone();
+        while let Some((x, r)) = s.uncons()? {
+            acc = f(acc, x)?;
+            if let Reduced(ref v) = acc {
+                return Ok((**v).clone());
+            }
+            s = r;
+        }
+        return Ok(acc);
+    }
+    for x in seq_arg(name, coll)? {
+        acc = f(acc, x)?;
+        if let Reduced(ref v) = acc {
+            return Ok((**v).clone());
+        }
     }
+    Ok(acc)
 }
 
-fn conj(a: MalArgs) -> MalRet {
+fn reduce(a: MalArgs) -> MalRet {
+    let (init, coll) = match a.len() {
+        2 if is_lazy(&a[1]) => match a[1].uncons()? {
+            Some((x, r)) => (x, r),
+            None => return a[0].apply(vec![]),
+        },
+        2 => {
+            let mut xs = seq_arg("reduce", &a[1])?;
+            match xs.pop_front() {
+                Some(x) => (x, list!(xs)),
+                None => return a[0].apply(vec![]),
+            }
+        }
+        _ => (a[1].clone(), a[2].clone()),
+    };
+    fold("reduce", &coll, init, |acc, x| a[0].apply(vec![acc, x]))
+}
+
+fn filter(name: &'static str, a: MalArgs, keep: bool) -> MalRet {
+    if a.len() == 1 {
+        return Ok(filter_xf(name, a[0].clone(), keep));
+    }
+    Ok(lazy_filter(a[0].clone(), seq_of(name, &a[1])?, keep))
+}
+
+fn lazy_filter(pred: MalVal, s: MalVal, keep: bool) -> MalVal {
+    lazy_seq(move || {
+        let mut s = s.clone();
+        while let Some((x, r)) = s.uncons()? {
+            if truthy(&pred.apply(vec![x.clone()])?) == keep {
+                return Ok(lazy_cons(x, lazy_filter(pred.clone(), r, keep)));
+            }
+            s = r;
+        }
+        Ok(Nil)
+    })
+}
+
+fn take(a: MalArgs) -> MalRet {
+    let n = count_arg("take", &a[0])?;
+    if a.len() == 1 {
+        return Ok(take_xf(n));
+    }
+    Ok(lazy_take(n, seq_of("take", &a[1])?))
+}
+
+fn lazy_take(n: usize, s: MalVal) -> MalVal {
+    lazy_seq(move || {
+        if n == 0 {
+            return Ok(Nil);
+        }
+        Ok(match s.uncons()? {
+            Some((x, r)) => lazy_cons(x, lazy_take(n - 1, r)),
+            None => Nil,
+        })
+    })
+}
+
+fn drop(a: MalArgs) -> MalRet {
+    let n = count_arg("drop", &a[0])?;
+    Ok(lazy_drop(n, seq_of("drop", &a[1])?))
+}
+
+fn lazy_drop(n: usize, s: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut s = s.clone();
+        for _ in 0..n {
+            match s.uncons()? {
+                Some((_, r)) => s = r,
+                None => return Ok(Nil),
+            }
+        }
+        Ok(s)
+    })
+}
+
+// (x (f x) (f (f x)) ...)
+fn iterate(f: MalVal, x: MalVal) -> MalVal {
+    lazy_cons(
+        x.clone(),
+        lazy_seq(move || Ok(iterate(f.clone(), f.apply(vec![x.clone()])?))),
+    )
+}
+
+fn repeat(a: MalArgs) -> MalRet {
+    match a.len() {
+        1 => Ok(repeat_seq(a[0].clone())),
+        _ => Ok(list!(vec![a[1].clone(); count_arg("repeat", &a[0])?])),
+    }
+}
+
+fn repeat_seq(x: MalVal) -> MalVal {
+    lazy_seq(move || Ok(lazy_cons(x.clone(), repeat_seq(x.clone()))))
+}
+
+fn cycle(xs: MalVec, i: usize) -> MalVal {
+    lazy_seq(move || {
+        Ok(match xs.get(i) {
+            Some(x) => lazy_cons(x.clone(), cycle(xs.clone(), (i + 1) % xs.len())),
+            None => Nil,
+        })
+    })
+}
+
+fn last(a: MalArgs) -> MalRet {
+    Ok(seq_arg("last", &a[0])?.last().cloned().unwrap_or(Nil))
+}
+
+fn butlast(a: MalArgs) -> MalRet {
+    let xs = seq_arg("butlast", &a[0])?;
+    match xs.len() {
+        0 | 1 => Ok(Nil),
+        n => Ok(list!(xs.take(n - 1))),
+    }
+}
+
+fn reverse(a: MalArgs) -> MalRet {
+    Ok(list!(seq_arg("reverse", &a[0])?
+        .into_iter()
+        .rev()
+        .collect::<MalVec>()))
+}
+
+fn range(a: MalArgs) -> MalRet {
+    let (start, end, step) = match a.len() {
+        0 => return Ok(range_from(Int(0))),
+        1 => (Int(0), a[0].clone(), Int(1)),
+        2 => (a[0].clone(), a[1].clone(), Int(1)),
+        _ => (a[0].clone(), a[1].clone(), a[2].clone()),
+    };
+    let dir = match num_cmp(&step, &Int(0)) {
+        Ok(Some(Ordering::Equal)) => return error("range: step must not be zero"),
+        Ok(Some(o)) => o,
+        _ => return type_error("range: expecting number args"),
+    };
+    let mut res = MalVec::new();
+    let mut x = start;
+    while num_cmp(&end, &x)
+        .map_err(|_| ErrTyped("wrong-type", "range: expecting number args".to_string()))?
+        == Some(dir)
+    {
+        res.push_back(x.clone());
+        x = num_op("range", &ADD, &x, &step)?;
+    }
+    Ok(list!(res))
+}
+
+fn range_from(x: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let next = num_op("range", &ADD, &x, &Int(1))?;
+        Ok(lazy_cons(x.clone(), range_from(next)))
+    })
+}
+
+// The natural order sort uses: numbers, strings, keywords, symbols and
+// chars among themselves, nil first, sequences element by element
+fn cmp_vals(a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    match (a, b) {
+        (Nil, Nil) => Ok(Ordering::Equal),
+        (Nil, _) => Ok(Ordering::Less),
+        (_, Nil) => Ok(Ordering::Greater),
+        (Bool(x), Bool(y)) => Ok(x.cmp(y)),
+        (Str(x), Str(y)) => Ok(x.cmp(y)),
+        // by name, not by id
+        (Sym(x), Sym(y)) => Ok((**x).cmp(&**y)),
+        (Keyword(x), Keyword(y)) => Ok(x.cmp(y)),
+        (Char(x), Char(y)) => Ok(x.cmp(y)),
+        (List(..), List(..))
+        | (Vector(..), Vector(..))
+        | (List(..), Vector(..))
+        | (Vector(..), List(..)) => {
+            let (x, y) = (a.elems().unwrap(), b.elems().unwrap());
+            let lens = x.len().cmp(&y.len());
+            for (p, q) in x.zip(y) {
+                match cmp_vals(p, q)? {
+                    Ordering::Equal => (),
+                    o => return Ok(o),
+                }
+            }
+            Ok(lens)
+        }
+        _ => match num_cmp(a, b) {
+            Ok(o) => Ok(o.unwrap_or(Ordering::Equal)),
+            Err(_) => Err(ErrTyped(
+                "wrong-type",
+                format!("cannot compare {} and {}", a.pr_str(true), b.pr_str(true)),
+            )),
+        },
+    }
+}
+
+// A comparator returns a number like compare, or is a boolean "less than"
+fn cmp_with(cmp: Option<&MalVal>, a: &MalVal, b: &MalVal) -> Result<Ordering, MalErr> {
+    let f = match cmp {
+        Some(f) => f,
+        None => return cmp_vals(a, b),
+    };
+    match f.apply(vec![a.clone(), b.clone()])? {
+        Bool(true) => Ok(Ordering::Less),
+        Bool(false) | Nil => match f.apply(vec![b.clone(), a.clone()])? {
+            Bool(false) | Nil => Ok(Ordering::Equal),
+            _ => Ok(Ordering::Greater),
+        },
+        n => num_cmp(&n, &Int(0)).ok().and_then(|o| o).ok_or_else(|| {
+            ErrTyped(
+                "wrong-type",
+                "comparator must return a number or boolean".to_string(),
+            )
+        }),
+    }
+}
+
+// A stable sort of (key, element) pairs, stopping at the first error
+fn sort_keyed(mut kxs: Vec<(MalVal, MalVal)>, cmp: Option<&MalVal>) -> MalRet {
+    let mut err = None;
+    kxs.sort_by(|(p, _), (q, _)| {
+        if err.is_some() {
+            return Ordering::Equal;
+        }
+        cmp_with(cmp, p, q).unwrap_or_else(|e| {
+            err = Some(e);
+            Ordering::Equal
+        })
+    });
+    match err {
+        Some(e) => Err(e),
+        None => Ok(list!(kxs.into_iter().map(|(_, x)| x).collect::<MalList>())),
+    }
+}
+
+fn sort(a: MalArgs) -> MalRet {
+    let (cmp, xs) = match a.len() {
+        1 => (None, seq_arg("sort", &a[0])?),
+        _ => (Some(&a[0]), seq_arg("sort", &a[1])?),
+    };
+    sort_keyed(xs.into_iter().map(|x| (x.clone(), x)).collect(), cmp)
+}
+
+fn sort_by(a: MalArgs) -> MalRet {
+    let (cmp, xs) = match a.len() {
+        2 => (None, seq_arg("sort-by", &a[1])?),
+        _ => (Some(&a[1]), seq_arg("sort-by", &a[2])?),
+    };
+    let mut kxs = vec![];
+    for x in xs {
+        kxs.push((a[0].apply(vec![x.clone()])?, x));
+    }
+    sort_keyed(kxs, cmp)
+}
+
+fn group_by(a: MalArgs) -> MalRet {
+    let mut hm = MalMap::default();
+    for x in seq_arg("group-by", &a[1])? {
+        match hm
+            .entry(a[0].apply(vec![x.clone()])?)
+            .or_insert_with(|| vector![])
+        {
+            Vector(v, _) => Rc::make_mut(v).push_back(x),
+            _ => unreachable!(),
+        }
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+fn frequencies(a: MalArgs) -> MalRet {
+    let mut hm = MalMap::default();
+    for x in seq_arg("frequencies", &a[0])? {
+        match hm.entry(x).or_insert(Int(0)) {
+            Int(n) => *n += 1,
+            _ => unreachable!(),
+        }
+    }
+    Ok(Hash(hm, Rc::new(Nil)))
+}
+
+// (partition n coll), (partition n step coll) or (partition n step pad coll):
+// only full partitions are kept, unless a pad fills in the last one
+fn partition(a: MalArgs) -> MalRet {
+    let n = count_arg("partition", &a[0])?;
+    let step = match a.len() {
+        2 => n,
+        _ => count_arg("partition", &a[1])?,
+    };
+    let pad = match a.len() {
+        4 => Some(seq_of("partition", &a[2])?),
+        _ => None,
+    };
+    let xs = seq_of("partition", &a[a.len() - 1])?;
+    partitions("partition", xs, n, step, pad, false)
+}
+
+// (partition-all n coll) or (partition-all n step coll) keeps the short
+// partitions at the end, (partition-all n) is a transducer
+fn partition_all(a: MalArgs) -> MalRet {
+    let n = count_arg("partition-all", &a[0])?;
+    match a.len() {
+        1 if n == 0 => error("partition-all: size and step must be positive"),
+        1 => Ok(partition_all_xf(n)),
+        2 => partitions(
+            "partition-all",
+            seq_of("partition-all", &a[1])?,
+            n,
+            n,
+            None,
+            true,
+        ),
+        _ => {
+            let step = count_arg("partition-all", &a[1])?;
+            let xs = seq_of("partition-all", &a[2])?;
+            partitions("partition-all", xs, n, step, None, true)
+        }
+    }
+}
+
+fn partitions(
+    name: &str,
+    xs: MalVal,
+    n: usize,
+    step: usize,
+    pad: Option<MalVal>,
+    all: bool,
+) -> MalRet {
+    if n == 0 || step == 0 {
+        return error(&format!("{}: size and step must be positive", name));
+    }
+    Ok(lazy_partitions(xs, n, step, pad, all))
+}
+
+fn lazy_partitions(xs: MalVal, n: usize, step: usize, pad: Option<MalVal>, all: bool) -> MalVal {
+    lazy_seq(move || {
+        let mut part = MalVec::new();
+        let mut s = xs.clone();
+        while part.len() < n {
+            match s.uncons()? {
+                Some((x, r)) => {
+                    part.push_back(x);
+                    s = r;
+                }
+                None => break,
+            }
+        }
+        if part.is_empty() {
+            return Ok(Nil);
+        }
+        if part.len() < n && !all {
+            let mut p = match &pad {
+                Some(p) => p.clone(),
+                None => return Ok(Nil),
+            };
+            while part.len() < n {
+                match p.uncons()? {
+                    Some((x, r)) => {
+                        part.push_back(x);
+                        p = r;
+                    }
+                    None => break,
+                }
+            }
+            return Ok(list!(vec![list!(part)]));
+        }
+        let rest = lazy_drop(step, xs.clone());
+        Ok(lazy_cons(
+            list!(part),
+            lazy_partitions(rest, n, step, pad.clone(), all),
+        ))
+    })
+}
+
+fn interleave(a: MalArgs) -> MalRet {
+    let seqs = a
+        .iter()
+        .map(|s| seq_of("interleave", s))
+        .collect::<Result<Vec<_>, _>>()?;
+    if seqs.is_empty() {
+        return Ok(list![]);
+    }
+    Ok(lazy_interleave(seqs))
+}
+
+// The first of each seq, then the second of each, up to the shortest
+fn lazy_interleave(seqs: Vec<MalVal>) -> MalVal {
+    lazy_seq(move || {
+        let mut firsts = vec![];
+        let mut rests = vec![];
+        for s in seqs.iter() {
+            match s.uncons()? {
+                Some((x, r)) => {
+                    firsts.push(x);
+                    rests.push(r);
+                }
+                None => return Ok(Nil),
+            }
+        }
+        Ok(firsts
+            .into_iter()
+            .rev()
+            .fold(lazy_interleave(rests), |tail, x| lazy_cons(x, tail)))
+    })
+}
+
+fn some(a: MalArgs) -> MalRet {
+    let mut s = seq_of("some", &a[1])?;
+    while let Some((x, r)) = s.uncons()? {
+        let found = a[0].apply(vec![x])?;
+        if truthy(&found) {
+            return Ok(found);
+        }
+        s = r;
+    }
+    Ok(Nil)
+}
+
+fn every_q(a: MalArgs) -> MalRet {
+    let mut s = seq_of("every?", &a[1])?;
+    while let Some((x, r)) = s.uncons()? {
+        if !truthy(&a[0].apply(vec![x])?) {
+            return Ok(Bool(false));
+        }
+        s = r;
+    }
+    Ok(Bool(true))
+}
+
+// Keys paired with values up to the shorter of the two
+fn zipmap(a: MalArgs) -> MalRet {
+    let mut ks = seq_of("zipmap", &a[0])?;
+    let mut vs = seq_of("zipmap", &a[1])?;
+    let mut kvs = vec![];
+    while let (Some((k, kr)), Some((v, vr))) = (ks.uncons()?, vs.uncons()?) {
+        kvs.push(k);
+        kvs.push(v);
+        ks = kr;
+        vs = vr;
+    }
+    hash_map(kvs)
+}
+
+// A transducer turns a reducing fn rf into another, which is called with
+// no args for an initial value, one to complete a result and two to step
+fn transducer(name: &'static str, xf: impl Fn(MalVal) -> MalVal + 'static) -> MalVal {
+    func(name, 1, Some(1), move |a| Ok(xf(a[0].clone())))
+}
+
+// A reducing fn that leaves all but stepping to rf
+fn stepper(
+    name: &'static str,
+    rf: MalVal,
+    step: impl Fn(&MalVal, MalVal, MalVal) -> MalRet + 'static,
+) -> MalVal {
+    func(name, 0, Some(2), move |mut a| match a.pop() {
+        Some(x) if a.len() == 1 => step(&rf, a.pop().unwrap(), x),
+        Some(x) => rf.apply(vec![x]),
+        None => rf.apply(a),
+    })
+}
+
+fn ensure_reduced(a: MalVal) -> MalVal {
+    match a {
+        Reduced(_) => a,
+        _ => Reduced(Rc::new(a)),
+    }
+}
+
+fn unreduced(a: MalVal) -> MalVal {
+    match a {
+        Reduced(v) => (*v).clone(),
+        _ => a,
+    }
+}
+
+fn map_xf(f: MalVal) -> MalVal {
+    transducer("map", move |rf| {
+        let f = f.clone();
+        stepper("map", rf, move |rf, acc, x| {
+            rf.apply(vec![acc, f.apply(vec![x])?])
+        })
+    })
+}
+
+fn filter_xf(name: &'static str, pred: MalVal, keep: bool) -> MalVal {
+    transducer(name, move |rf| {
+        let pred = pred.clone();
+        stepper(name, rf, move |rf, acc, x| {
+            if truthy(&pred.apply(vec![x.clone()])?) == keep {
+                rf.apply(vec![acc, x])
+            } else {
+                Ok(acc)
+            }
+        })
+    })
+}
+
+fn take_xf(n: usize) -> MalVal {
+    transducer("take", move |rf| {
+        // each use of the transducer counts down from n afresh
+        let left = Cell::new(n);
+        stepper("take", rf, move |rf, acc, x| {
+            let n = left.get();
+            if n == 0 {
+                return Ok(ensure_reduced(acc));
+            }
+            left.set(n - 1);
+            let acc = rf.apply(vec![acc, x])?;
+            Ok(if n == 1 { ensure_reduced(acc) } else { acc })
+        })
+    })
+}
+
+fn partition_all_xf(n: usize) -> MalVal {
+    transducer("partition-all", move |rf| {
+        let part = RefCell::new(MalVec::new());
+        func("partition-all", 0, Some(2), move |a| match a.len() {
+            2 => {
+                part.borrow_mut().push_back(a[1].clone());
+                if part.borrow().len() < n {
+                    return Ok(a[0].clone());
+                }
+                let full = part.replace(MalVec::new());
+                rf.apply(vec![a[0].clone(), vector!(full)])
+            }
+            // the partition left over goes in before completing
+            1 => {
+                let rest = part.replace(MalVec::new());
+                let acc = if rest.is_empty() {
+                    a[0].clone()
+                } else {
+                    unreduced(rf.apply(vec![a[0].clone(), vector!(rest)])?)
+                };
+                rf.apply(vec![acc])
+            }
+            _ => rf.apply(a),
+        })
+    })
+}
+
+// (transduce xform f coll) or (transduce xform f init coll)
+fn transduce(a: MalArgs) -> MalRet {
+    let (xform, f, coll) = (&a[0], &a[1], &a[a.len() - 1]);
+    let rf = xform.apply(vec![f.clone()])?;
+    let init = match a.len() {
+        4 => a[2].clone(),
+        _ => f.apply(vec![])?,
+    };
+    let acc = fold("transduce", coll, init, |acc, x| rf.apply(vec![acc, x]))?;
+    rf.apply(vec![acc])
+}
+
+// (into to from) or (into to xform from)
+fn into(a: MalArgs) -> MalRet {
+    match a.len() {
+        2 => fold("into", &a[1], a[0].clone(), |acc, x| conj(vec![acc, x])),
+        _ => transduce(vec![
+            a[1].clone(),
+            func("conj", 0, None, conj),
+            a[0].clone(),
+            a[2].clone(),
+        ]),
+    }
+}
+
+// (sequence coll) or (sequence xform coll). The transducer steps
+// through coll only as far as the elements of the result are taken.
+fn sequence(a: MalArgs) -> MalRet {
+    if a.len() == 1 {
+        return seq_of("sequence", &a[0]);
+    }
+    let out = Rc::new(RefCell::new(VecDeque::new()));
+    let buf = out.clone();
+    // collects what the transducer steps out, there is no accumulator
+    let collect = func("sequence", 0, Some(2), move |a| {
+        if a.len() == 2 {
+            buf.borrow_mut().push_back(a[1].clone());
+        }
+        Ok(Nil)
+    });
+    Ok(xf_seq(Rc::new(XfSeq {
+        rf: a[0].apply(vec![collect])?,
+        rest: RefCell::new(seq_of("sequence", &a[1])?),
+        out,
+        done: Cell::new(false),
+    })))
+}
+
+// A (sequence xform coll) as far as it is realized: what is left of coll,
+// and what the transducer stepped out of it that is not taken yet
+struct XfSeq {
+    rf: MalVal,
+    rest: RefCell<MalVal>,
+    out: Rc<RefCell<VecDeque<MalVal>>>,
+    done: Cell<bool>,
+}
+
+fn xf_seq(xs: Rc<XfSeq>) -> MalVal {
+    lazy_seq(move || loop {
+        if let Some(x) = xs.out.borrow_mut().pop_front() {
+            return Ok(lazy_cons(x, xf_seq(xs.clone())));
+        }
+        if xs.done.get() {
+            return Ok(Nil);
+        }
+        let rest = xs.rest.replace(Nil);
+        let stopped = match rest.uncons()? {
+            Some((x, r)) => {
+                xs.rest.replace(r);
+                matches!(xs.rf.apply(vec![Nil, x])?, Reduced(_))
+            }
+            None => true,
+        };
+        // completing may step out what the transducer held back
+        if stopped {
+            xs.done.set(true);
+            xs.rf.apply(vec![Nil])?;
+        }
+    })
+}
+
+// (comp f g h) calls h with the args, then g, then f
+fn comp(a: MalArgs) -> MalRet {
+    match a.len() {
+        0 => Ok(func("identity", 1, Some(1), |a| Ok(a[0].clone()))),
+        1 => Ok(a[0].clone()),
+        _ => Ok(func("comp", 0, None, move |args| {
+            let mut fs = a.iter().rev();
+            let mut r = fs.next().unwrap().apply(args)?;
+            for f in fs {
+                r = f.apply(vec![r])?;
+            }
+            Ok(r)
+        })),
+    }
+}
+
+// strings are indexed by Unicode scalar value, as with nth and count
+
+fn str_arg<'a>(name: &str, a: &'a MalVal) -> Result<&'a str, MalErr> {
+    match a {
+        Str(s) => Ok(s),
+        _ => Err(ErrTyped(
+            "wrong-type",
+            format!("{}: expecting a string", name),
+        )),
+    }
+}
+
+// What the search builtins look for: a string or a char
+fn needle(name: &str, a: &MalVal) -> Result<String, MalErr> {
+    match a {
+        Str(s) => Ok(s.clone()),
+        Char(c) => Ok(c.to_string()),
+        _ => Err(ErrTyped(
+            "wrong-type",
+            format!("{}: expecting a string or char", name),
+        )),
+    }
+}
+
+fn index_arg(name: &str, a: &MalVal) -> Result<i64, MalErr> {
+    match a {
+        Int(i) => Ok(*i),
+        _ => Err(ErrTyped(
+            "wrong-type",
+            format!("{}: expecting an integer index", name),
+        )),
+    }
+}
+
+fn subs(a: MalArgs) -> MalRet {
+    let s = str_arg("subs", &a[0])?;
+    let n = s.chars().count() as i64;
+    let start = index_arg("subs", &a[1])?;
+    let end = match a.get(2) {
+        Some(e) => index_arg("subs", e)?,
+        None => n,
+    };
+    if start < 0 || end > n || start > end {
+        return error(&format!(
+            "subs: range {}..{} out of bounds for {}",
+            start, end, n
+        ));
+    }
+    Ok(Str(s
+        .chars()
+        .skip(start as usize)
+        .take((end - start) as usize)
+        .collect()))
+}
+
+// Like Clojure, trailing empty strings are dropped unless given a limit
+fn split(a: MalArgs) -> MalRet {
+    let s = str_arg("split", &a[0])?;
+    let limit = match a.get(2) {
+        Some(l) => Some(index_arg("split", l)?).filter(|l| *l > 0),
+        None => None,
+    };
+    let mut parts: Vec<String> = match (&a[1], limit) {
+        (Regex(re), Some(l)) => re.splitn(s, l as usize).map(String::from).collect(),
+        (Regex(re), None) => re.split(s).map(String::from).collect(),
+        (sep, limit) => {
+            let sep = needle("split", sep)?;
+            match limit {
+                _ if sep.is_empty() => s.chars().map(|c| c.to_string()).collect(),
+                Some(l) => s.splitn(l as usize, &sep[..]).map(String::from).collect(),
+                None => s.split(&sep[..]).map(String::from).collect(),
+            }
+        }
+    };
+    if limit.is_none() {
+        while parts.len() > 1 && parts.last().is_some_and(|p| p.is_empty()) {
+            parts.pop();
+        }
+    }
+    Ok(vector!(parts.into_iter().map(Str).collect::<MalVec>()))
+}
+
+fn join(a: MalArgs) -> MalRet {
+    let (sep, coll) = match a.len() {
+        1 => (String::new(), &a[0]),
+        _ => (a[0].pr_str(false), &a[1]),
+    };
+    let strs: Vec<String> = match seq(vec![coll.clone()])? {
+        List(l, _) => l.iter().map(|x| x.pr_str(false)).collect(),
+        _ => vec![],
+    };
+    Ok(Str(strs.join(&sep)))
+}
+
+fn str_test(name: &str, a: &MalArgs, test: fn(&str, &str) -> bool) -> MalRet {
+    let s = str_arg(name, &a[0])?;
+    Ok(Bool(test(s, &needle(name, &a[1])?)))
+}
+
+fn index_of(a: MalArgs) -> MalRet {
+    let s = str_arg("index-of", &a[0])?;
+    let n = needle("index-of", &a[1])?;
+    let from = match a.get(2) {
+        Some(f) => index_arg("index-of", f)?.max(0) as usize,
+        None => 0,
+    };
+    let start = match s
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(s.len()))
+        .nth(from)
+    {
+        Some(start) => start,
+        None => return Ok(Nil),
+    };
+    match s[start..].find(&n[..]) {
+        Some(i) => Ok(Int((from + s[start..start + i].chars().count()) as i64)),
+        None => Ok(Nil),
+    }
+}
+
+// With a regex the replacement can refer to groups as $1, or be a
+// function called with each match
+fn replace(a: MalArgs) -> MalRet {
+    let s = str_arg("replace", &a[0])?;
+    let re = match a[1] {
+        Regex(ref re) => re,
+        _ => {
+            let m = needle("replace", &a[1])?;
+            return Ok(Str(s.replace(&m[..], &needle("replace", &a[2])?)));
+        }
+    };
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(s) {
+        let m = caps.get(0).unwrap();
+        out.push_str(&s[last..m.start()]);
+        match a[2] {
+            Str(ref r) => caps.expand(r, &mut out),
+            Char(c) => out.push(c),
+            Func(..) | MalFunc { .. } => {
+                out.push_str(&a[2].apply(vec![match_val(&caps)])?.pr_str(false))
+            }
+            _ => return type_error("replace: expecting a string, char or function replacement"),
+        }
+        last = m.end();
+    }
+    out.push_str(&s[last..]);
+    Ok(Str(out))
+}
+
+fn blank_q(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+        Nil => Ok(Bool(true)),
+        Str(ref s) => Ok(Bool(s.trim().is_empty())),
+        _ => type_error("blank?: expecting a string"),
+    }
+}
+
+// Widths and precisions are at most this, more than Rust's formatting
+// takes and long before the padding would exhaust memory
+const FORMAT_MAX: usize = u16::MAX as usize;
+
+fn format_digits(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    what: &str,
+) -> Result<Option<usize>, MalErr> {
+    let mut n = None;
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        let m = n
+            .unwrap_or(0usize)
+            .checked_mul(10)
+            .and_then(|m| m.checked_add(d as usize))
+            .filter(|m| *m <= FORMAT_MAX)
+            .ok_or_else(|| {
+                ErrString(format!(
+                    "format: {} out of range, at most {}",
+                    what, FORMAT_MAX
+                ))
+            })?;
+        n = Some(m);
+        chars.next();
+    }
+    Ok(n)
+}
+
+// C style exponent, at least two digits: 1.500000e+02
+fn exp_str(f: f64, precision: usize) -> String {
+    let s = format!("{:.*e}", precision, f);
+    match s.split_once('e') {
+        Some((m, e)) => match e.strip_prefix('-') {
+            Some(e) => format!("{}e-{:0>2}", m, e),
+            None => format!("{}e+{:0>2}", m, e),
+        },
+        None => s,
+    }
+}
+
+// The text for one conversion, and for numbers whether they are negative
+fn format_arg(
+    conv: char,
+    precision: Option<usize>,
+    arg: &MalVal,
+) -> Result<(Option<bool>, String), MalErr> {
+    let bad = || {
+        ErrTyped(
+            "wrong-type",
+            format!("format: %{} does not accept {}", conv, arg.pr_str(true)),
+        )
+    };
+    match conv {
+        's' => {
+            let s = arg.pr_str(false);
+            Ok((
+                None,
+                precision.map_or(s.clone(), |p| s.chars().take(p).collect()),
+            ))
+        }
+        'c' => match arg {
+            Char(c) => Ok((None, c.to_string())),
+            Int(i) => match i.to_u32().and_then(std::char::from_u32) {
+                Some(c) => Ok((None, c.to_string())),
+                None => Err(bad()),
+            },
+            _ => Err(bad()),
+        },
+        'd' | 'x' | 'X' | 'o' => {
+            let n = to_big(arg).ok_or_else(bad)?;
+            let digits = match conv {
+                'x' => n.abs().to_str_radix(16),
+                'X' => n.abs().to_str_radix(16).to_uppercase(),
+                'o' => n.abs().to_str_radix(8),
+                _ => n.abs().to_string(),
+            };
+            Ok((Some(n.is_negative()), digits))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
-        _ => error("conj: called with non-seq"),
+        'f' | 'e' => {
+            let f = to_f64(arg).ok_or_else(bad)?;
+            let p = precision.unwrap_or(6);
+            let digits = match conv {
+                'e' => exp_str(f.abs(), p),
+                _ => format!("{:.*}", p, f.abs()),
+            };
+            Ok((Some(f.is_sign_negative()), digits))
+        }
+        _ => Err(ErrString(format!("format: unknown conversion %{}", conv))),
     }
 }
 
-fn seq(a: MalArgs) -> MalRet {
+// printf style %[flags][width][.precision]conversion, with flags from
+// "-+ 0" and conversions s c d x X o f e, plus %% and %n
+fn format(a: MalArgs) -> MalRet {
+    let fmt = str_arg("format", &a[0])?;
+    let mut args = a[1..].iter();
+    let mut chars = fmt.chars().peekable();
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut flags = String::new();
+        while let Some(f) = chars.peek().filter(|f| "-+ 0".contains(**f)) {
+            flags.push(*f);
+            chars.next();
+        }
+        let width = format_digits(&mut chars, "width")?.unwrap_or(0);
+        let precision = match chars.peek() {
+            Some('.') => {
+                chars.next();
+                Some(format_digits(&mut chars, "precision")?.unwrap_or(0))
+            }
+            _ => None,
+        };
+        let (num, body) = match chars.next() {
+            Some('%') => (None, String::from("%")),
+            Some('n') => (None, String::from("\n")),
+            Some(conv) => match args.next() {
+                Some(arg) => format_arg(conv, precision, arg)?,
+                None => return error("format: not enough arguments"),
+            },
+            None => return error("format: incomplete conversion at end of string"),
+        };
+        let sign = match num {
+            Some(true) => "-",
+            Some(false) if flags.contains('+') => "+",
+            Some(false) if flags.contains(' ') => " ",
+            _ => "",
+        };
+        let fill = width.saturating_sub(sign.len() + body.chars().count());
+        if flags.contains('-') {
+            out.push_str(&format!("{}{}{}", sign, body, " ".repeat(fill)));
+        } else if flags.contains('0') && num.is_some() {
+            out.push_str(&format!("{}{}{}", sign, "0".repeat(fill), body));
+        } else {
+            out.push_str(&format!("{}{}{}", " ".repeat(fill), sign, body));
+        }
+    }
+    Ok(Str(out))
+}
+
+fn regex_arg<'a>(name: &str, a: &'a MalVal) -> Result<&'a regex::Regex, MalErr> {
+    match a {
+        Regex(re) => Ok(re),
+        _ => Err(ErrTyped(
+            "wrong-type",
+            format!("{}: expecting a regex", name),
+        )),
+    }
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
+        Regex(_) => Ok(a[0].clone()),
+        Str(ref s) => match regex::Regex::new(s) {
+            Ok(re) => Ok(Regex(Rc::new(re))),
+            Err(e) => error(&format!("re-pattern: {}", e)),
+        },
+        _ => type_error("re-pattern: expecting a string"),
+    }
+}
+
+// The whole match followed by each group, nil where a group took no part
+fn groups_val(caps: &regex::Captures) -> MalVal {
+    vector!(caps
+        .iter()
+        .map(|m| m.map_or(Nil, |m| Str(m.as_str().to_string())))
+        .collect::<MalVec>())
+}
+
+// Like Clojure, a match is just its text when the regex has no groups
+fn match_val(caps: &regex::Captures) -> MalVal {
+    match caps.len() {
+        1 => Str(caps[0].to_string()),
+        _ => groups_val(caps),
+    }
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    let re = regex_arg("re-find", &a[0])?;
+    let s = str_arg("re-find", &a[1])?;
+    Ok(re.captures(s).map_or(Nil, |caps| match_val(&caps)))
+}
+
+fn re_matches(a: MalArgs) -> MalRet {
+    let re = regex_arg("re-matches", &a[0])?;
+    let s = str_arg("re-matches", &a[1])?;
+    // anchoring both ends, rather than checking the span of a match,
+    // also finds whole matches that are not the leftmost-first one
+    let whole = match regex::Regex::new(&format!(r"\A(?:{})\z", re.as_str())) {
+        Ok(whole) => whole,
+        Err(e) => return error(&format!("re-matches: {}", e)),
+    };
+    Ok(whole.captures(s).map_or(Nil, |caps| match_val(&caps)))
+}
+
+fn re_seq(a: MalArgs) -> MalRet {
+    let re = regex_arg("re-seq", &a[0])?;
+    let s = str_arg("re-seq", &a[1])?;
+    let ms: MalVec = re.captures_iter(s).map(|caps| match_val(&caps)).collect();
+    if ms.is_empty() {
+        Ok(Nil)
+    } else {
+        Ok(list!(ms))
+    }
+}
+
+fn re_groups(a: MalArgs) -> MalRet {
+    let re = regex_arg("re-groups", &a[0])?;
+    let s = str_arg("re-groups", &a[1])?;
+    Ok(re.captures(s).map_or(Nil, |caps| groups_val(&caps)))
+}
+
+// ex-info values are maps of :message, :data and an optional :cause
+fn ex_info(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Str(_), Hash(..)) | (Str(_), Nil) => {
+            let mut kvs = vec![
+                keyword("message"),
+                a[0].clone(),
+                keyword("data"),
+                a[1].clone(),
+            ];
+            if let Some(cause) = a.get(2) {
+                kvs.push(keyword("cause"));
+                kvs.push(cause.clone());
+            }
+            hash_map(kvs)
         }
-        Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
+        _ => type_error("ex-info: expecting a string and a map"),
+    }
+}
+
+// Accessors are nil for anything that is not an exception map
+fn ex_get(a: &MalVal, key: &str) -> MalRet {
+    match a {
+        Hash(hm, _) => Ok(hm.get(&keyword(key)).cloned().unwrap_or(Nil)),
+        _ => Ok(Nil),
     }
 }
 
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+    let builtins: Vec<(&'static str, usize, Option<usize>, MalFn)> = vec![
+        ("=", 1, None, equal),
+        ("throw", 1, Some(1), |a| Err(ErrMalVal(a[0].clone()))),
+        ("ex-info", 2, Some(3), ex_info),
+        ("ex-message", 1, Some(1), |a| ex_get(&a[0], "message")),
+        ("ex-data", 1, Some(1), |a| ex_get(&a[0], "data")),
+        ("ex-cause", 1, Some(1), |a| ex_get(&a[0], "cause")),
+        ("nil?", 1, Some(1), fn_is_type!(Nil)),
+        ("true?", 1, Some(1), fn_is_type!(Bool(true))),
+        ("false?", 1, Some(1), fn_is_type!(Bool(false))),
+        ("symbol", 1, Some(1), symbol),
+        ("symbol?", 1, Some(1), fn_is_type!(Sym(_))),
+        ("string?", 1, Some(1), fn_is_type!(Str(_))),
+        ("char?", 1, Some(1), fn_is_type!(Char(_))),
+        ("char", 1, Some(1), char),
+        ("keyword", 1, Some(2), make_keyword),
+        ("keyword?", 1, Some(1), fn_is_type!(Keyword(_))),
+        ("name", 1, Some(1), name),
+        ("namespace", 1, Some(1), namespace),
         (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
+            "number?",
+            1,
+            Some(1),
+            fn_is_type!(Int(_), Big(_), Ratio(_), Float(_)),
         ),
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("int?", 1, Some(1), fn_is_type!(Int(_))),
+        ("float?", 1, Some(1), fn_is_type!(Float(_))),
+        ("ratio?", 1, Some(1), fn_is_type!(Ratio(_))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            1,
+            Some(1),
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_)),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
+            1,
+            Some(1),
+            fn_is_type!(MalFunc{is_macro,..} if is_macro),
+        ),
+        ("pr-str", 0, None, |a| {
+            realize_printed(&a)?;
+            Ok(Str(pr_seq(&a, true, "", "", " ")))
+        }),
+        ("str", 0, None, |a| {
+            realize_printed(&a)?;
+            Ok(Str(pr_seq(&a, false, "", "", "")))
+        }),
+        ("prn", 0, None, |a| {
+            realize_printed(&a)?;
+            println!("{}", pr_seq(&a, true, "", "", " "));
+            Ok(Nil)
+        }),
+        ("println", 0, None, |a| {
+            realize_printed(&a)?;
+            println!("{}", pr_seq(&a, false, "", "", " "));
+            Ok(Nil)
+        }),
+        ("subs", 2, Some(3), subs),
+        ("split", 2, Some(3), split),
+        ("join", 1, Some(2), join),
+        (
+            "trim",
+            1,
+            Some(1),
+            fn_str!(|s: String| Ok(Str(s.trim().to_string()))),
+        ),
+        (
+            "triml",
+            1,
+            Some(1),
+            fn_str!(|s: String| Ok(Str(s.trim_start().to_string()))),
+        ),
+        (
+            "trimr",
+            1,
+            Some(1),
+            fn_str!(|s: String| Ok(Str(s.trim_end().to_string()))),
+        ),
+        (
+            "upper-case",
+            1,
+            Some(1),
+            fn_str!(|s: String| Ok(Str(s.to_uppercase()))),
+        ),
+        (
+            "lower-case",
+            1,
+            Some(1),
+            fn_str!(|s: String| Ok(Str(s.to_lowercase()))),
+        ),
+        ("starts-with?", 2, Some(2), |a| {
+            str_test("starts-with?", &a, |s, n| s.starts_with(n))
+        }),
+        ("ends-with?", 2, Some(2), |a| {
+            str_test("ends-with?", &a, |s, n| s.ends_with(n))
+        }),
+        ("includes?", 2, Some(2), |a| {
+            str_test("includes?", &a, |s, n| s.contains(n))
+        }),
+        ("index-of", 2, Some(3), index_of),
+        ("replace", 3, Some(3), replace),
+        ("blank?", 1, Some(1), blank_q),
+        ("format", 1, None, format),
+        ("re-pattern", 1, Some(1), re_pattern),
+        ("re-find", 2, Some(2), re_find),
+        ("re-matches", 2, Some(2), re_matches),
+        ("re-seq", 2, Some(2), re_seq),
+        ("re-groups", 2, Some(2), re_groups),
+        ("read-string", 1, Some(1), fn_str!(|s| { read_str(s) })),
+        ("readline", 1, Some(1), readline),
+        ("slurp", 1, Some(1), fn_str!(|f| { slurp(f) })),
+        ("read-file", 1, Some(1), fn_str!(|f| { read_file_forms(f) })),
+        ("<", 1, None, |a| compare("<", a, |o| o == Ordering::Less)),
+        ("<=", 1, None, |a| {
+            compare("<=", a, |o| o != Ordering::Greater)
+        }),
+        (">", 1, None, |a| {
+            compare(">", a, |o| o == Ordering::Greater)
+        }),
+        (">=", 1, None, |a| compare(">=", a, |o| o != Ordering::Less)),
+        ("+", 0, None, add),
+        ("-", 1, None, sub),
+        ("*", 0, None, mul),
+        ("/", 1, None, div),
+        ("quot", 2, Some(2), |a| {
+            num_div_op("quot", &QUOT, &a[0], &a[1])
+        }),
+        ("rem", 2, Some(2), |a| num_div_op("rem", &REM, &a[0], &a[1])),
+        ("mod", 2, Some(2), |a| num_div_op("mod", &MOD, &a[0], &a[1])),
+        ("inc", 1, Some(1), |a| num_op("inc", &ADD, &a[0], &Int(1))),
+        ("dec", 1, Some(1), |a| num_op("dec", &SUB, &a[0], &Int(1))),
+        ("max", 1, None, |a| extremum("max", a, Ordering::Greater)),
+        ("min", 1, None, |a| extremum("min", a, Ordering::Less)),
+        ("abs", 1, Some(1), abs),
+        (
+            "floor",
+            1,
+            Some(1),
+            fn_round!(f64::floor, BigRational::floor),
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        ("ceil", 1, Some(1), fn_round!(f64::ceil, BigRational::ceil)),
         (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
+            "round",
+            1,
+            Some(1),
+            fn_round!(f64::round, BigRational::round),
         ),
+        ("double", 1, Some(1), double),
+        ("int", 1, Some(1), int),
+        ("bigint", 1, Some(1), bigint),
+        ("numerator", 1, Some(1), numerator),
+        ("denominator", 1, Some(1), denominator),
+        ("time-ms", 0, Some(0), time_ms),
         (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            "sequential?",
+            1,
+            Some(1),
+            fn_is_type!(List(_, _), Vector(_, _), LazySeq(_)),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+        ("list", 0, None, |a| Ok(list!(a))),
+        ("list?", 1, Some(1), fn_is_type!(List(_, _))),
+        ("vector", 0, None, |a| Ok(vector!(a))),
+        ("vector?", 1, Some(1), fn_is_type!(Vector(_, _))),
+        ("hash-map", 0, None, |a| hash_map(a)),
+        ("map?", 1, Some(1), fn_is_type!(Hash(_, _))),
+        ("hash-set", 0, None, hash_set),
+        ("set", 1, Some(1), set),
+        ("set?", 1, Some(1), fn_is_type!(Set(_, _))),
+        ("disj", 1, None, disj),
+        ("union", 0, None, union),
+        ("intersection", 1, None, intersection),
+        ("difference", 1, None, difference),
+        ("subset?", 2, Some(2), subset_q),
+        ("superset?", 2, Some(2), superset_q),
+        ("assoc", 1, None, assoc),
+        ("dissoc", 1, None, dissoc),
+        ("get", 2, Some(2), get),
+        ("contains?", 2, Some(2), contains_q),
+        ("keys", 1, Some(1), keys),
+        ("vals", 1, Some(1), vals),
+        ("vec", 1, Some(1), vec),
+        ("cons", 2, Some(2), cons),
+        ("concat", 0, None, concat),
+        ("empty?", 1, Some(1), |a| a[0].empty_q()),
+        ("nth", 2, Some(2), nth),
+        ("first", 1, Some(1), first),
+        ("rest", 1, Some(1), rest),
+        ("count", 1, Some(1), |a| a[0].count()),
+        ("apply", 2, None, apply),
+        ("map", 1, None, map),
+        ("mapcat", 2, None, mapcat),
+        ("reduce", 2, Some(3), reduce),
+        ("reduced", 1, Some(1), |a| {
+            Ok(Reduced(Rc::new(a[0].clone())))
+        }),
+        ("reduced?", 1, Some(1), fn_is_type!(Reduced(_))),
+        ("transduce", 3, Some(4), transduce),
+        ("into", 2, Some(3), into),
+        ("sequence", 1, Some(2), sequence),
+        ("comp", 0, None, comp),
+        ("filter", 1, Some(2), |a| filter("filter", a, true)),
+        ("remove", 1, Some(2), |a| filter("remove", a, false)),
+        ("take", 1, Some(2), take),
+        ("drop", 2, Some(2), drop),
+        ("last", 1, Some(1), last),
+        ("butlast", 1, Some(1), butlast),
+        ("reverse", 1, Some(1), reverse),
+        ("range", 0, Some(3), range),
+        ("iterate", 2, Some(2), |a| {
+            Ok(iterate(a[0].clone(), a[1].clone()))
+        }),
+        ("repeat", 1, Some(2), repeat),
+        ("cycle", 1, Some(1), |a| {
+            Ok(cycle(seq_arg("cycle", &a[0])?, 0))
+        }),
+        ("sort", 1, Some(2), sort),
+        ("sort-by", 2, Some(3), sort_by),
+        ("group-by", 2, Some(2), group_by),
+        ("frequencies", 1, Some(1), frequencies),
+        ("partition", 2, Some(4), partition),
+        ("partition-all", 1, Some(3), partition_all),
+        ("interleave", 0, None, interleave),
+        ("some", 2, Some(2), some),
+        ("every?", 2, Some(2), every_q),
+        ("zipmap", 2, Some(2), zipmap),
+        ("conj", 0, None, conj),
+        ("seq", 1, Some(1), seq),
+        ("meta", 1, Some(1), |a| a[0].get_meta()),
+        ("with-meta", 2, Some(2), |a| a[0].clone().with_meta(&a[1])),
+        ("atom", 1, Some(1), |a| Ok(atom(&a[0]))),
+        ("atom?", 1, Some(1), fn_is_type!(Atom(_))),
+        ("deref", 1, Some(1), |a| a[0].deref()),
+        ("reset!", 2, Some(2), |a| a[0].reset_bang(&a[1])),
+        ("swap!", 2, None, |a| a[0].swap_bang(&a[1..].to_vec())),
+    ];
+    builtins
+        .into_iter()
+        .map(|(name, min, max, f)| (name, func(name, min, max, f)))
+        .collect()
 }
@@ -3,13 +3,15 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ErrString, ErrTyped};
+use crate::types::MalVal::{List, Nil, Sym};
+use crate::types::{arity_error, error, Elems, MalErr, MalRet, MalVal, Symbol};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    // locals that analysis addressed by position instead of by name
+    slots: RefCell<Vec<MalVal>>,
     pub outer: Option<Env>,
 }
 
@@ -19,42 +21,101 @@ pub type Env = Rc<EnvStruct>;
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
+    env_frame(outer, vec![])
+}
+
+// An env whose locals are in slots, numbered in the order they are bound
+pub fn env_frame(outer: Option<Env>, slots: Vec<MalVal>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(slots),
         outer: outer,
     })
 }
 
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+pub fn env_bind(
+    outer: Option<Env>,
+    mbinds: MalVal,
+    exprs: Vec<MalVal>,
+    name: &str,
+) -> Result<Env, MalErr> {
     let env = env_new(outer);
-    match mbinds {
-        List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
-                    }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
-                    }
-                }
+    match mbinds.elems() {
+        Some(binds) => {
+            let vals = bind_values(binds, exprs, name)?;
+            for (b, v) in mbinds.elems().unwrap().filter(|b| !is_amp(b)).zip(vals) {
+                env_set(&env, b.clone(), v)?;
             }
             Ok(env)
         }
-        _ => Err(ErrString("env_bind binds not List/Vector".to_string())),
+        None => Err(ErrString("env_bind binds not List/Vector".to_string())),
+    }
+}
+
+// Like env_bind, but the values go in slots in the order of mbinds
+pub fn env_bind_slots(
+    outer: Option<Env>,
+    mbinds: &MalVal,
+    exprs: Vec<MalVal>,
+    name: &str,
+) -> Result<Env, MalErr> {
+    match mbinds.elems() {
+        Some(binds) => Ok(env_frame(outer, bind_values(binds, exprs, name)?)),
+        None => Err(ErrString("env_bind binds not List/Vector".to_string())),
+    }
+}
+
+// The values of the symbols in binds: the exprs before any '&', then a
+// list of the rest for the symbol after it
+fn bind_values(
+    mut binds: Elems,
+    mut exprs: Vec<MalVal>,
+    name: &str,
+) -> Result<Vec<MalVal>, MalErr> {
+    let len = binds.len();
+    let (fixed, rest) = match binds.position(is_amp) {
+        Some(i) => match binds.next() {
+            Some(r @ Sym(_)) if !is_amp(r) && len == i + 2 => (i, true),
+            _ => {
+                return Err(ErrTyped(
+                    "syntax",
+                    format!("{}: '&' must be followed by exactly one symbol", name),
+                ))
+            }
+        },
+        None => (len, false),
+    };
+    if exprs.len() < fixed || (!rest && exprs.len() > fixed) {
+        return Err(arity_error(exprs.len(), name));
+    }
+    if rest {
+        let more = exprs.split_off(fixed);
+        exprs.push(list!(more));
     }
+    Ok(exprs)
+}
+
+fn is_amp(b: &MalVal) -> bool {
+    matches!(b, Sym(s) if s.id() == Symbol::AMP)
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
-    match (env.data.borrow().contains_key(key), env.outer.clone()) {
-        (true, _) => Some(env.clone()),
-        (false, Some(o)) => env_find(&o, key),
-        _ => None,
+pub fn env_find(env: &Env, key: &Symbol) -> Option<Env> {
+    let mut env = env;
+    loop {
+        let data = env.data.borrow();
+        // most envs only have slots, skip hashing the key for them
+        if !data.is_empty() && data.contains_key(key) {
+            return Some(env.clone());
+        }
+        env = env.outer.as_ref()?;
     }
 }
 
+fn not_found(key: &MalVal) -> MalErr {
+    ErrTyped("not-found", format!("'{}' not found", key.pr_str(false)))
+}
+
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
         Sym(ref s) => match env_find(env, s) {
@@ -62,18 +123,47 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
                 .data
                 .borrow()
                 .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
+                .ok_or_else(|| not_found(key))?
                 .clone()),
-            _ => error(&format!("'{}' not found", s)),
+            _ => Err(not_found(key)),
         },
         _ => error("Env.get called with non-Str"),
     }
 }
 
+// The local in slot of the env depth envs out from env. Only the steps
+// that address locals by position call these.
+#[allow(dead_code)]
+pub fn env_get_slot(env: &Env, depth: usize, slot: usize, key: &MalVal) -> MalRet {
+    let mut env = env;
+    for _ in 0..depth {
+        env = env.outer.as_ref().ok_or_else(|| not_found(key))?;
+    }
+    let slots = env.slots.borrow();
+    slots.get(slot).cloned().ok_or_else(|| not_found(key))
+}
+
+#[allow(dead_code)]
+pub fn env_push(env: &Env, val: MalVal) {
+    env.slots.borrow_mut().push(val);
+}
+
+// Like env_get_slot, the slot must be bound already
+#[allow(dead_code)]
+pub fn env_set_slot(env: &Env, depth: usize, slot: usize, key: &MalVal, val: MalVal) -> MalRet {
+    let mut env = env;
+    for _ in 0..depth {
+        env = env.outer.as_ref().ok_or_else(|| not_found(key))?;
+    }
+    let mut slots = env.slots.borrow_mut();
+    *slots.get_mut(slot).ok_or_else(|| not_found(key))? = val.clone();
+    Ok(val)
+}
+
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+            env.data.borrow_mut().insert(s.clone(), val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
@@ -81,5 +171,5 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env.data.borrow_mut().insert(Symbol::new(key), val);
 }
@@ -1,5 +1,33 @@
-use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use std::cell::RefCell;
+
+use crate::env::{env_get, Env};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio,
+    Reduced, Regex, Set, Str, Sym, Vector,
+};
+use crate::types::{symbol, MalVal};
+
+thread_local! {
+    // The env *print-length* is looked up in, once a step defines it
+    static PRINT_ENV: RefCell<Option<Env>> = const { RefCell::new(None) };
+}
+
+#[allow(dead_code)]
+pub fn set_print_env(env: &Env) {
+    PRINT_ENV.with(|p| *p.borrow_mut() = Some(env.clone()));
+}
+
+// The value of *print-length*: how many elements of a collection are
+// printed before the rest is elided, None to print them all
+pub fn print_length() -> Option<usize> {
+    PRINT_ENV.with(|p| match &*p.borrow() {
+        Some(env) => match env_get(env, &symbol("*print-length*")) {
+            Ok(MalVal::Int(n)) if n >= 0 => Some(n as usize),
+            _ => None,
+        },
+        None => None,
+    })
+}
 
 fn escape_str(s: &str) -> String {
     s.chars()
@@ -13,6 +41,35 @@ fn escape_str(s: &str) -> String {
         .join("")
 }
 
+// Debug formatting is the shortest representation that parses back to
+// the same f64 and always keeps a '.' or exponent, so it reads as Float
+fn float_str(f: f64) -> String {
+    if f.is_nan() {
+        String::from("##NaN")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "##Inf" } else { "##-Inf" })
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+// The names read_atom accepts for whitespace and control characters
+pub const CHAR_NAMES: [(&str, char); 6] = [
+    ("newline", '\n'),
+    ("space", ' '),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("backspace", '\u{8}'),
+    ("formfeed", '\u{c}'),
+];
+
+fn char_str(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, nc)| *nc == c) {
+        Some((name, _)) => format!("\\{}", name),
+        None => format!("\\{}", c),
+    }
+}
+
 impl MalVal {
     pub fn pr_str(&self, print_readably: bool) -> String {
         match self {
@@ -20,42 +77,75 @@ impl MalVal {
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            Big(b) if print_readably => format!("{}N", b),
+            Big(b) => b.to_string(),
+            Ratio(r) => r.to_string(),
+            Float(f) => float_str(*f),
+            Char(c) if print_readably => char_str(*c),
+            Char(c) => c.to_string(),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
-            Hash(hm, _) => {
-                let l: Vec<MalVal> = hm
-                    .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
-                    .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+            Sym(s) => s.to_string(),
+            Keyword(k) => format!(":{}", k),
+            List(l, _) => pr_coll(l.iter().map(|x| x.pr_str(print_readably)), "(", ")"),
+            Vector(l, _) => pr_coll(l.iter().map(|x| x.pr_str(print_readably)), "[", "]"),
+            Hash(hm, _) => pr_coll(
+                hm.iter()
+                    .map(|(k, v)| pr_seq(vec![k, v], print_readably, "", "", " ")),
+                "{",
+                "}",
+            ),
+            Set(s, _) => pr_coll(s.iter().map(|x| x.pr_str(print_readably)), "#{", "}"),
+            // realize_printed has already raised any error forcing it meets
+            LazySeq(_) => {
+                let mut s = self.clone();
+                let elems = std::iter::from_fn(|| match s.uncons() {
+                    Ok(Some((x, r))) => {
+                        s = r;
+                        Some(x.pr_str(print_readably))
+                    }
+                    _ => None,
+                });
+                pr_coll(elems, "(", ")")
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
+            Func(b, _) => format!("#<fn {}>", b.name),
             MalFunc {
                 ast: a, params: p, ..
             } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
             Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            Reduced(v) => format!("(reduced {})", v.pr_str(true)),
+            Regex(r) if print_readably => format!("#\"{}\"", r.as_str().replace('"', "\\\"")),
+            Regex(r) => r.as_str().to_string(),
+        }
+    }
+}
+
+// At most *print-length* elements, then "..." if any were left out
+fn pr_coll(elems: impl Iterator<Item = String>, start: &str, end: &str) -> String {
+    let limit = print_length();
+    let mut strs: Vec<String> = vec![];
+    for (i, x) in elems.enumerate() {
+        if Some(i) == limit {
+            strs.push("...".to_string());
+            break;
         }
+        strs.push(x);
     }
+    format!("{}{}{}", start, strs.join(" "), end)
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a>(
+    seq: impl IntoIterator<Item = &'a MalVal>,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
 ) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
+    let strs: Vec<String> = seq.into_iter().map(|x| x.pr_str(print_readably)).collect();
     format!("{}{}{}", start, strs.join(join), end)
 }
@@ -1,14 +1,29 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
 use regex::{Captures, Regex};
 use std::rc::Rc;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::printer::CHAR_NAMES;
+use crate::types::MalErr::{ErrString, ErrTyped};
+use crate::types::MalVal::{Big, Bool, Char, Float, Int, List, Nil, Str, Vector};
+use crate::types::{
+    error, hash_map, hash_set, int_val, keyword, ratio, symbol, MalArgs, MalErr, MalList, MalRet,
+    MalVal,
+};
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
 
 #[derive(Debug, Clone)]
 struct Reader {
-    tokens: Vec<String>,
+    tokens: Vec<Token>,
     pos: usize,
+    file: Option<String>,
 }
 
 impl Reader {
@@ -18,6 +33,7 @@ impl Reader {
             .tokens
             .get(self.pos - 1)
             .ok_or(ErrString("underflow".to_string()))?
+            .text
             .to_string())
     }
     fn peek(&self) -> Result<String, MalErr> {
@@ -25,24 +41,65 @@ impl Reader {
             .tokens
             .get(self.pos)
             .ok_or(ErrString("underflow".to_string()))?
+            .text
             .to_string())
     }
+    // Source position of the token at idx, recorded as list metadata when
+    // reading a file. Forms read from strings get none.
+    fn pos_meta(&self, idx: usize) -> MalRet {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(Nil),
+        };
+        let t = &self.tokens[idx];
+        hash_map(vec![
+            keyword("line"),
+            Int(t.line as i64),
+            keyword("column"),
+            Int(t.column as i64),
+            keyword("file"),
+            Str(file.to_string()),
+        ])
+    }
+    // Reader errors point at the offending token when reading a file
+    fn error_at(&self, idx: usize, msg: &str) -> MalRet {
+        let e = ErrTyped("reader", msg.to_string());
+        match (&self.file, self.tokens.get(idx)) {
+            (Some(file), Some(t)) => {
+                Err(e.unwind(&Nil, Some(format!("{}:{}:{}", file, t.line, t.column))))
+            }
+            _ => Err(e),
+        }
+    }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+fn tokenize(str: &str) -> Vec<Token> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+            r###"[\s,]*(~@|#\{|\\(?:\w+|\S)|[\[\]{}()'`~^@]|#?"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
 
     let mut res = vec![];
+    let (mut line, mut line_start, mut last) = (1, 0, 0);
     for cap in RE.captures_iter(str) {
+        let start = cap.get(1).unwrap().start();
+        for (i, c) in str[last..start].char_indices() {
+            if c == '\n' {
+                line += 1;
+                line_start = last + i + 1;
+            }
+        }
+        last = start;
         if cap[1].starts_with(";") {
             continue;
         }
-        res.push(String::from(&cap[1]));
+        res.push(Token {
+            text: String::from(&cap[1]),
+            line,
+            column: str[line_start..start].chars().count() + 1,
+        });
     }
     res
 }
@@ -57,9 +114,25 @@ fn unescape_str(s: &str) -> String {
     .to_string()
 }
 
+// Patterns are taken raw, #"\d" is the regex \d, except that \" is a '"'
+fn unescape_regex(s: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+    }
+    RE.replace_all(s, |caps: &Captures| match &caps[1] {
+        "\"" => "\"".to_string(),
+        c => format!("\\{}", c),
+    })
+    .to_string()
+}
+
 fn read_atom(rdr: &mut Reader) -> MalRet {
     lazy_static! {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+        static ref BIG_RE: Regex = Regex::new(r"^(-?[0-9]+)N$").unwrap();
+        static ref RATIO_RE: Regex = Regex::new(r"^(-?[0-9]+)/([0-9]+)$").unwrap();
+        static ref FLOAT_RE: Regex =
+            Regex::new(r"^-?(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)(?:[eE][-+]?[0-9]+)?$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
     let token = rdr.next()?;
@@ -67,29 +140,69 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
         "nil" => Ok(Nil),
         "false" => Ok(Bool(false)),
         "true" => Ok(Bool(true)),
+        "##Inf" => Ok(Float(f64::INFINITY)),
+        "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+        "##NaN" => Ok(Float(f64::NAN)),
         _ => {
             if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
+                Ok(int_val(token.parse().unwrap()))
+            } else if let Some(caps) = BIG_RE.captures(&token) {
+                Ok(Big(Rc::new(caps[1].parse().unwrap())))
+            } else if let Some(caps) = RATIO_RE.captures(&token) {
+                let (n, d): (BigInt, BigInt) = (caps[1].parse().unwrap(), caps[2].parse().unwrap());
+                if d.is_zero() {
+                    return rdr.error_at(rdr.pos - 1, "Divide by zero");
+                }
+                Ok(ratio(BigRational::new(n, d)))
+            } else if FLOAT_RE.is_match(&token) {
+                Ok(Float(token.parse().unwrap()))
+            } else if let Some(re) = token.strip_prefix('#').filter(|re| STR_RE.is_match(re)) {
+                match regex::Regex::new(&unescape_regex(&re[1..re.len() - 1])) {
+                    Ok(r) => Ok(MalVal::Regex(Rc::new(r))),
+                    Err(e) => rdr.error_at(rdr.pos - 1, &format!("invalid regex: {}", e)),
+                }
             } else if STR_RE.is_match(&token) {
                 Ok(Str(unescape_str(&token[1..token.len() - 1])))
-            } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
+            } else if token.starts_with("\"") || token.starts_with("#\"") {
+                rdr.error_at(rdr.pos - 1, "expected '\"', got EOF")
+            } else if let Some(c) = token.strip_prefix('\\') {
+                read_char(rdr, c)
+            } else if let Some(kw) = token.strip_prefix(':') {
+                Ok(keyword(kw))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(symbol(&token))
             }
         }
     }
 }
 
+// \a, \newline and friends, or \uXXXX for any Unicode scalar value
+fn read_char(rdr: &Reader, name: &str) -> MalRet {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Char(c));
+    }
+    if let Some((_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+        return Ok(Char(*c));
+    }
+    let code = match name.strip_prefix('u') {
+        Some(hex) if hex.len() == 4 => u32::from_str_radix(hex, 16).ok(),
+        _ => None,
+    };
+    match code.and_then(std::char::from_u32) {
+        Some(c) => Ok(Char(c)),
+        None => rdr.error_at(rdr.pos - 1, &format!("unsupported character: \\{}", name)),
+    }
+}
+
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let start = rdr.pos;
+    let open = rdr.next()?;
     loop {
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => return rdr.error_at(start, &format!("expected '{}', got EOF", end)),
         };
         if token == end {
             break;
@@ -97,11 +210,12 @@ fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
+    match &open[..] {
+        "(" => Ok(List(MalList::from(seq), Rc::new(rdr.pos_meta(start)?))),
+        "[" => Ok(vector!(seq)),
+        "{" => hash_map(seq),
+        "#{" => hash_set(seq),
+        _ => error("read_seq unknown open value"),
     }
 }
 
@@ -110,35 +224,35 @@ fn read_form(rdr: &mut Reader) -> MalRet {
     match &token[..] {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![symbol("quote"), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![symbol("quasiquote"), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![symbol("unquote"), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![symbol("splice-unquote"), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![symbol("with-meta"), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![symbol("deref"), read_form(rdr)?])
         }
-        ")" => error("unexpected ')'"),
+        ")" => rdr.error_at(rdr.pos, "unexpected ')'"),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
+        "]" => rdr.error_at(rdr.pos, "unexpected ']'"),
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
+        "}" => rdr.error_at(rdr.pos, "unexpected '}'"),
+        "{" | "#{" => read_seq(rdr, "}"),
         _ => read_atom(rdr),
     }
 }
@@ -152,5 +266,20 @@ pub fn read_str(str: String) -> MalRet {
     read_form(&mut Reader {
         pos: 0,
         tokens: tokens,
+        file: None,
     })
 }
+
+// Read every form in the source of a file, positions carry its name
+pub fn read_file(str: String, file: &str) -> Result<MalArgs, MalErr> {
+    let mut rdr = Reader {
+        pos: 0,
+        tokens: tokenize(&str),
+        file: Some(file.to_string()),
+    };
+    let mut forms = vec![];
+    while rdr.pos < rdr.tokens.len() {
+        forms.push(read_form(&mut rdr)?);
+    }
+    Ok(forms)
+}
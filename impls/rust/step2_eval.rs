@@ -5,7 +5,12 @@ use fnv::FnvHashMap;
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -16,9 +21,12 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{
+    error, format_error, func, hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal,
+};
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -35,7 +43,7 @@ fn read(str: &str) -> MalRet {
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(sym) => Ok(env
-            .get(sym)
+            .get(&sym[..])
             .ok_or(ErrString(format!("'{}' not found", sym)))?
             .clone()),
         List(v, _) => {
@@ -53,11 +61,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut xs: MalArgs = vec![];
+            for x in s.iter() {
+                xs.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(xs)
         }
         _ => Ok(ast.clone()),
     }
@@ -72,7 +88,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
                     let ref f = el[0].clone();
-                    f.apply(el[1..].to_vec())
+                    f.apply(el.iter().skip(1).cloned().collect())
                 }
                 _ => error("expected a list"),
             }
@@ -107,10 +123,22 @@ fn main() {
     }
 
     let mut repl_env = Env::default();
-    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    repl_env.insert("/".to_string(), func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    repl_env.insert(
+        "+".to_string(),
+        func("+", 2, Some(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    repl_env.insert(
+        "-".to_string(),
+        func("-", 2, Some(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    repl_env.insert(
+        "*".to_string(),
+        func("*", 2, Some(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    repl_env.insert(
+        "/".to_string(),
+        func("/", 2, Some(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
@@ -1,12 +1,16 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -16,10 +20,13 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, Int, List, Nil, Set, Sym, Vector};
+use crate::types::{
+    error, format_error, func, hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal,
+};
 mod env;
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
@@ -47,11 +54,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut xs: MalArgs = vec![];
+            for x in s.iter() {
+                xs.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(xs)
         }
         _ => Ok(ast.clone()),
     }
@@ -72,8 +87,8 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
                     match a1 {
-                        List(ref binds, _) | Vector(ref binds, _) => {
-                            for (b, e) in binds.iter().tuples() {
+                        List(..) | Vector(..) => {
+                            for (b, e) in a1.elems().unwrap().tuples() {
                                 match b {
                                     Sym(_) => {
                                         let _ = env_set(
@@ -97,7 +112,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
                         let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        f.apply(el.iter().skip(1).cloned().collect())
                     }
                     _ => error("expected a list"),
                 },
@@ -133,10 +148,26 @@ fn main() {
     }
 
     let repl_env = env_new(None);
-    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    env_sets(&repl_env, "/", func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    env_sets(
+        &repl_env,
+        "+",
+        func("+", 2, Some(2), |a: MalArgs| int_op(|i, j| i + j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "-",
+        func("-", 2, Some(2), |a: MalArgs| int_op(|i, j| i - j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "*",
+        func("*", 2, Some(2), |a: MalArgs| int_op(|i, j| i * j, a)),
+    );
+    env_sets(
+        &repl_env,
+        "/",
+        func("/", 2, Some(2), |a: MalArgs| int_op(|i, j| i / j, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
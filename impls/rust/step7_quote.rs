@@ -1,12 +1,16 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -15,8 +19,11 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    error, format_error, hash_map, hash_set, symbol, Elems, MalArgs, MalErr, MalList, MalRet,
+    MalVal,
+};
 mod env;
 mod printer;
 mod reader;
@@ -31,20 +38,20 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: Elems) -> MalVal {
     let mut acc = list![];
-    for elt in elts.iter().rev() {
+    for elt in elts.collect::<Vec<_>>().into_iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![symbol("concat"), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![symbol("cons"), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -59,10 +66,10 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
-        },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+            return qq_iter(ast.elems().unwrap());
+        }
+        Vector(..) => return list![symbol("vec"), qq_iter(ast.elems().unwrap())],
+        Hash(_, _) | Sym(_) => return list![symbol("quote"), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -85,11 +92,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut xs: MalArgs = vec![];
+            for x in s.iter() {
+                xs.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(xs)
         }
         _ => Ok(ast.clone()),
     }
@@ -106,15 +121,17 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
                 let a0 = &l[0];
                 match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
-                    }
+                    Sym(ref a0sym) if a0sym == "def!" => env_set(
+                        &env,
+                        l[1].clone(),
+                        eval(l[2].clone(), env.clone())?.named(&l[1]),
+                    ),
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
+                            List(..) | Vector(..) => {
+                                for (b, e) in a1.elems().unwrap().tuples() {
                                     match b {
                                         Sym(_) => {
                                             let _ = env_set(
@@ -143,7 +160,15 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         continue 'tco;
                     }
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(
+                            &list!(l
+                                .iter()
+                                .skip(1)
+                                .take(l.len() - 2)
+                                .cloned()
+                                .collect::<MalList>()),
+                            &env,
+                        )? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -174,7 +199,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             env: env,
                             params: Rc::new(a1),
                             is_macro: false,
+                            name: None,
                             meta: Rc::new(Nil),
+                            code: None,
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
@@ -187,7 +214,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args = el.iter().skip(1).cloned().collect();
                             match f {
                                 Func(_, _) => f.apply(args),
                                 MalFunc {
@@ -198,7 +225,8 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 } => {
                                     let a = &**mast;
                                     let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
+                                    env =
+                                        env_bind(Some(menv.clone()), p.clone(), args, f.fn_name())?;
                                     ast = a.clone();
                                     continue 'tco;
                                 }
@@ -226,6 +254,7 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
     let exp = eval(ast, env.clone())?;
+    exp.realize_printed()?;
     Ok(print(&exp))
 }
 
@@ -244,7 +273,11 @@ fn main() {
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(
+        &repl_env,
+        "*ARGV*",
+        list!(args.map(Str).collect::<MalList>()),
+    );
 
     // core.mal: defined using the language itself
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
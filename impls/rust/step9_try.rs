@@ -1,12 +1,16 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -15,9 +19,12 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ErrMalVal, ErrString, ErrTrace, ErrTyped};
+use crate::types::MalVal::{Bool, Func, Hash, LazySeq, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    error, format_error, hash_map, hash_set, symbol, Elems, MalArgs, MalErr, MalList, MalRet,
+    MalVal,
+};
 mod env;
 mod printer;
 mod reader;
@@ -32,20 +39,29 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+// The value bound by catch*, without any position tag
+fn error_value(e: &MalErr) -> MalVal {
+    match e {
+        ErrMalVal(mv) => mv.clone(),
+        ErrString(s) | ErrTyped(_, s) => Str(s.to_string()),
+        ErrTrace(e, _) => error_value(e),
+    }
+}
+
+fn qq_iter(elts: Elems) -> MalVal {
     let mut acc = list![];
-    for elt in elts.iter().rev() {
+    for elt in elts.collect::<Vec<_>>().into_iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![symbol("concat"), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![symbol("cons"), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -60,10 +76,10 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
-        },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+            return qq_iter(ast.elems().unwrap());
+        }
+        Vector(..) => return list![symbol("vec"), qq_iter(ast.elems().unwrap())],
+        Hash(_, _) | Sym(_) => return list![symbol("quote"), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -73,7 +89,9 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
         List(v, _) => match v[0] {
             Sym(ref s) => match env_find(env, s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => {
+                        Some((f, v.iter().skip(1).cloned().collect()))
+                    }
                     _ => None,
                 },
                 _ => None,
@@ -116,11 +134,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut xs: MalArgs = vec![];
+            for x in s.iter() {
+                xs.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            hash_set(xs)
         }
         _ => Ok(ast.clone()),
     }
@@ -149,15 +175,17 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
                 let a0 = &l[0];
                 match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
-                    }
+                    Sym(ref a0sym) if a0sym == "def!" => env_set(
+                        &env,
+                        l[1].clone(),
+                        eval(l[2].clone(), env.clone())?.named(&l[1]),
+                    ),
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
                         let (a1, a2) = (l[1].clone(), l[2].clone());
                         match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
+                            List(..) | Vector(..) => {
+                                for (b, e) in a1.elems().unwrap().tuples() {
                                     match b {
                                         Sym(_) => {
                                             let _ = env_set(
@@ -194,6 +222,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 ast,
                                 env,
                                 params,
+                                name,
                                 ..
                             } => Ok(env_set(
                                 &env,
@@ -204,8 +233,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                     env: env.clone(),
                                     params: params.clone(),
                                     is_macro: true,
+                                    name: name.clone(),
                                     meta: Rc::new(Nil),
-                                },
+                                    code: None,
+                                }
+                                .named(&a1),
                             )?),
                             _ => error("set_macro on non-function"),
                         }
@@ -216,28 +248,36 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             (_, e) => return e,
                         }
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
+                    Sym(ref a0sym) if a0sym == "try*" => {
+                        match eval(l[1].clone(), env.clone()).and_then(MalVal::force_first) {
+                            Err(ref e) if l.len() >= 3 => {
+                                let exc = error_value(e);
+                                match l[2].clone() {
+                                    List(c, _) => {
+                                        let catch_env = env_bind(
+                                            Some(env.clone()),
+                                            list!(vec![c[1].clone()]),
+                                            vec![exc],
+                                            "catch*",
+                                        )?;
+                                        eval(c[2].clone(), catch_env)
+                                    }
+                                    _ => error("invalid catch block"),
                                 }
-                                _ => error("invalid catch block"),
                             }
+                            res => res,
                         }
-                        res => res,
-                    },
+                    }
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(
+                            &list!(l
+                                .iter()
+                                .skip(1)
+                                .take(l.len() - 2)
+                                .cloned()
+                                .collect::<MalList>()),
+                            &env,
+                        )? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -268,7 +308,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             env: env,
                             params: Rc::new(a1),
                             is_macro: false,
+                            name: None,
                             meta: Rc::new(Nil),
+                            code: None,
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
@@ -281,7 +323,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let args = el.iter().skip(1).cloned().collect();
                             match f {
                                 Func(_, _) => f.apply(args),
                                 MalFunc {
@@ -292,7 +334,8 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 } => {
                                     let a = &**mast;
                                     let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
+                                    env =
+                                        env_bind(Some(menv.clone()), p.clone(), args, f.fn_name())?;
                                     ast = a.clone();
                                     continue 'tco;
                                 }
@@ -303,6 +346,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     },
                 }
             }
+            // a macro may expand to a lazy seq, which is a call all the same
+            LazySeq(_) => {
+                ast = list!(ast.realize()?);
+                continue 'tco;
+            }
             _ => eval_ast(&ast, &env),
         };
 
@@ -320,6 +368,7 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
     let exp = eval(ast, env.clone())?;
+    exp.realize_printed()?;
     Ok(print(&exp))
 }
 
@@ -338,7 +387,11 @@ fn main() {
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(
+        &repl_env,
+        "*ARGV*",
+        list!(args.map(Str).collect::<MalList>()),
+    );
 
     // core.mal: defined using the language itself
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
@@ -1,14 +1,19 @@
 #![allow(non_snake_case)]
 
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
+extern crate im_rc;
 extern crate itertools;
+extern crate num_bigint;
+extern crate num_integer;
+extern crate num_rational;
+extern crate num_traits;
 extern crate regex;
 
 extern crate rustyline;
@@ -17,15 +22,25 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalErr::{ErrMalVal, ErrString, ErrTrace, ErrTyped};
+use crate::types::MalVal::{
+    Bool, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Set, Str, Sym, Vector,
+};
+use crate::types::{
+    error, format_error, func, hash_map, hash_set, keyword, lazy_seq, symbol, type_error, Elems,
+    MalArgs, MalErr, MalList, MalMap, MalRet, MalVal, Symbol,
+};
 mod env;
 mod printer;
+use crate::printer::set_print_env;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{
+    env_bind_slots, env_find, env_frame, env_get, env_get_slot, env_new, env_push, env_set,
+    env_set_slot, env_sets, Env,
+};
 #[macro_use]
 mod core;
+mod vm;
 
 // read
 fn read(str: &str) -> MalRet {
@@ -34,20 +49,117 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn syntax_error<T>(form: &str, expected: &str) -> Result<T, MalErr> {
+    Err(ErrTyped(
+        "syntax",
+        format!("Syntax error in {}: expected {}", form, expected),
+    ))
+}
+
+// "file:line:column" of a list read by read-file
+fn source_pos(form: &MalVal) -> Option<String> {
+    let hm = match form {
+        List(_, meta) => match **meta {
+            Hash(ref hm, _) => hm.clone(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match (
+        hm.get(&keyword("file")),
+        hm.get(&keyword("line")),
+        hm.get(&keyword("column")),
+    ) {
+        (Some(Str(f)), Some(Int(l)), Some(Int(c))) => Some(format!("{}:{}:{}", f, l, c)),
+        _ => None,
+    }
+}
+
+// Quoted data read from a file, without the positions of its lists, which
+// are only there for error reports
+fn strip_pos(form: &MalVal) -> MalVal {
+    match form {
+        List(l, _) if source_pos(form).is_some() => {
+            List(l.iter().map(strip_pos).collect(), Rc::new(Nil))
+        }
+        List(l, meta) => List(l.iter().map(strip_pos).collect(), meta.clone()),
+        Vector(v, meta) => Vector(Rc::new(v.iter().map(strip_pos).collect()), meta.clone()),
+        Hash(hm, meta) => Hash(
+            hm.iter()
+                .map(|(k, v)| (strip_pos(k), strip_pos(v)))
+                .collect(),
+            meta.clone(),
+        ),
+        Set(s, meta) => Set(s.iter().map(strip_pos).collect(), meta.clone()),
+        _ => form.clone(),
+    }
+}
+
+// Interpreter errors with a type reach catch* clauses with a selector as
+// {:type :kind :message "..."}
+#[allow(clippy::mutable_key_type)]
+fn error_map(kind: &str, msg: &str) -> MalVal {
+    let mut hm = MalMap::default();
+    hm.insert(keyword("type"), keyword(kind));
+    hm.insert(keyword("message"), Str(msg.to_string()));
+    Hash(hm, Rc::new(Nil))
+}
+
+// The value bound by catch*, without the stack trace. A clause without a
+// selector gets interpreter errors as their message string, as mal always
+// has, and so does one with a selector for errors without a type.
+fn error_value(e: &MalErr, typed: bool) -> MalVal {
+    match e {
+        ErrMalVal(mv) => mv.clone(),
+        ErrTyped(kind, s) if typed => error_map(kind, s),
+        ErrString(s) | ErrTyped(_, s) => Str(s.to_string()),
+        ErrTrace(e, _) => error_value(e, typed),
+    }
+}
+
+fn error_trace(e: &MalErr) -> MalVal {
+    match e {
+        ErrTrace(_, trace) => vector!(trace
+            .frames
+            .iter()
+            .chain(trace.pos.iter())
+            .map(|f| Str(f.to_string()))
+            .collect::<Vec<_>>()),
+        _ => vector![],
+    }
+}
+
+thread_local! {
+    // Caught values and traces of the running catch* blocks, innermost last
+    static CAUGHT: RefCell<Vec<(MalVal, MalVal)>> = const { RefCell::new(vec![]) };
+}
+
+// (stack-trace e) in a catch* block gives the frames e unwound, innermost
+// first, and nil for values that were not caught
+fn stack_trace(a: MalArgs) -> MalRet {
+    Ok(CAUGHT.with(|c| {
+        c.borrow()
+            .iter()
+            .rev()
+            .find(|(exc, _)| *exc == a[0])
+            .map_or(Nil, |(_, trace)| trace.clone())
+    }))
+}
+
+fn qq_iter(elts: Elems) -> MalVal {
     let mut acc = list![];
-    for elt in elts.iter().rev() {
+    for elt in elts.collect::<Vec<_>>().into_iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
-                    if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                    if s.id() == Symbol::SPLICE_UNQUOTE {
+                        acc = list![symbol("concat"), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![symbol("cons"), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -57,25 +169,27 @@ fn quasiquote(ast: &MalVal) -> MalVal {
         List(v, _) => {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
-                    if s == "unquote" {
+                    if s.id() == Symbol::UNQUOTE {
                         return v[1].clone();
                     }
                 }
             }
-            return qq_iter(&v);
-        },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+            return qq_iter(ast.elems().unwrap());
+        }
+        Vector(..) => return list![symbol("vec"), qq_iter(ast.elems().unwrap())],
+        Hash(_, _) | Set(_, _) | Sym(_) => return list![symbol("quote"), ast.clone()],
         _ => ast.clone(),
     }
 }
 
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
-        List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+        List(v, _) => match v.head() {
+            Some(a0 @ Sym(s)) => match env_find(env, s) {
+                Some(e) => match env_get(&e, a0) {
+                    Ok(f @ MalFunc { is_macro: true, .. }) => {
+                        Some((f, v.iter().skip(1).cloned().collect()))
+                    }
                     _ => None,
                 },
                 _ => None,
@@ -100,218 +214,901 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
-fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
+// What analyze makes of a form: macros are expanded and special forms are
+// picked out once, rather than each time the form is evaluated
+enum Node {
+    Const(MalVal),
+    // a global, or a symbol def! bound in a local env
+    Var(MalVal),
+    // a let*, loop*, fn* or catch* local: its slot in the env so many envs out
+    Local(usize, usize, MalVal),
+    // a local a fn* body sees once its let* binds it, the node for what
+    // the symbol meant before that
+    Late(usize, usize, MalVal, Box<Node>),
+    Vector(Vec<Node>),
+    Hash(Vec<Node>),
+    Set(Vec<Node>),
+    Def(MalVal, Box<Node>),
+    // def! of a local, which sets its slot so the body sees the new value
+    DefLocal(usize, usize, MalVal, Box<Node>),
+    DefMacro(MalVal, Box<Node>),
+    Let(Vec<Node>, Box<Node>),
+    // binds like let*, and recur rebinds it and runs the body again
+    Loop(Vec<Node>, Box<Node>),
+    Recur(Vec<Node>),
+    Do(Vec<Node>),
+    If(Box<Node>, Box<Node>, Box<Node>),
+    Fn(Rc<FnCode>),
+    Macroexpand(MalVal),
+    Try(Box<Node>, Vec<Catch>, Vec<Node>),
+    LazySeq(Rc<Node>),
+    Eval(Box<Node>),
+    Call(Box<Node>, Vec<Node>),
+    // a list read by read-file, at "file:line:column"
+    Pos(Rc<str>, Box<Node>),
+    // a form that failed to analyze, raising the error only once it is
+    // reached, as catch* and untaken branches expect
+    Throw(MalErr),
+}
+
+// A catch* clause: its selector (None catches everything) and the handler,
+// run in an env with the caught value in its one slot
+type Catch<C = Node> = (Option<C>, C);
+
+// A fn* form, shared by all the functions it makes. The body is analyzed
+// on the first call, so it may use macros defined after the fn* form, and
+// checked with the form the fn* is in, so that a misplaced recur is an
+// error before any call.
+struct FnCode {
+    params: Rc<MalVal>,
+    body: Rc<MalVal>,
+    // the locals the fn* form is in, and how many of the innermost scope's
+    // were bound when it was analyzed
+    scope: Locals,
+    scope_len: usize,
+    node: RefCell<Option<Rc<Node>>>,
+}
+
+impl FnCode {
+    fn new(params: &MalVal, body: &MalVal, env: &Env, locals: &Locals) -> Result<FnCode, MalErr> {
+        let code = FnCode {
+            params: Rc::new(params.clone()),
+            body: Rc::new(body.clone()),
+            scope: locals.clone(),
+            scope_len: bound(locals),
+            node: RefCell::new(None),
+        };
+        check_recur_form(body, None, true, env, locals)?;
+        Ok(code)
+    }
+
+    fn node(&self, env: &Env) -> Result<Rc<Node>, MalErr> {
+        if let Some(node) = &*self.node.borrow() {
+            return Ok(node.clone());
+        }
+        let node = Rc::new(self.analyze_body(env)?);
+        *self.node.borrow_mut() = Some(node.clone());
+        Ok(node)
+    }
+
+    fn analyze_body(&self, env: &Env) -> Result<Node, MalErr> {
+        let names = match &*self.params {
+            ps @ List(..) | ps @ Vector(..) => ps
+                .elems()
+                .unwrap()
+                .filter_map(|p| match p {
+                    Sym(s) if s.id() != Symbol::AMP => Some(s.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        let scope = Some(new_scope(names, &self.scope, self.scope_len));
+        let node = analyze(&self.body, env, &scope)?;
+        check_recur(&node, None, true)?;
+        Ok(node)
+    }
+}
+
+// The fn* form and closure env of a function made by this step
+fn fn_code(f: &MalVal) -> Option<(Rc<FnCode>, Env)> {
+    match f {
+        MalFunc {
+            code: Some(code),
+            env,
+            ..
+        } => Some((code.clone().downcast().ok()?, env.clone())),
+        _ => None,
+    }
+}
+
+// The names of the locals in the envs that keep them in slots, innermost
+// first. Locals shadow macros of the same name. A let* scope grows as its
+// bindings are analyzed, as its env does when they are evaluated.
+struct Scope {
+    names: RefCell<Vec<Symbol>>,
+    outer: Locals,
+    // the names of outer bound when this scope was made: a fn* body in a
+    // let* init is analyzed once the let* has all its names, but can be
+    // called before the later ones are bound
+    outer_len: usize,
+}
+
+type Locals = Option<Rc<Scope>>;
+
+fn new_scope(names: Vec<Symbol>, outer: &Locals, outer_len: usize) -> Rc<Scope> {
+    Rc::new(Scope {
+        names: RefCell::new(names),
+        outer: outer.clone(),
+        outer_len,
+    })
+}
+
+// The number of names bound so far in the innermost scope
+fn bound(locals: &Locals) -> usize {
+    locals.as_ref().map_or(0, |sc| sc.names.borrow().len())
+}
+
+// The depth and slot of the local named s
+fn resolve(locals: &Locals, s: &Symbol) -> Option<(usize, usize)> {
+    let mut scope = locals.as_ref();
+    let mut depth = 0;
+    let mut len = bound(locals);
+    while let Some(sc) = scope {
+        if let Some(slot) = sc.names.borrow()[..len].iter().rposition(|n| n == s) {
+            return Some((depth, slot));
+        }
+        scope = sc.outer.as_ref();
+        len = sc.outer_len;
+        depth += 1;
+    }
+    None
+}
+
+// The depth and slot of a local named s that a let* binds after the fn*
+// form being analyzed, unless one bound before then is closer
+fn resolve_late(locals: &Locals, s: &Symbol) -> Option<(usize, usize)> {
+    let mut scope = locals.as_ref();
+    let mut depth = 0;
+    let mut len = bound(locals);
+    while let Some(sc) = scope {
+        let names = sc.names.borrow();
+        if let Some(slot) = names[len..].iter().rposition(|n| n == s) {
+            return Some((depth, len + slot));
+        }
+        if names[..len].contains(s) {
+            return None;
+        }
+        scope = sc.outer.as_ref();
+        len = sc.outer_len;
+        depth += 1;
+    }
+    None
+}
+
+fn analyze(ast: &MalVal, env: &Env, locals: &Locals) -> Result<Node, MalErr> {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
-        List(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+        Sym(s) => {
+            let node = match resolve(locals, s) {
+                Some((depth, slot)) => Node::Local(depth, slot, ast.clone()),
+                None => Node::Var(ast.clone()),
+            };
+            Ok(match resolve_late(locals, s) {
+                Some((depth, slot)) => Node::Late(depth, slot, ast.clone(), Box::new(node)),
+                None => node,
+            })
+        }
+        List(l, _) if !l.is_empty() => {
+            let pos = source_pos(ast);
+            match analyze_list(ast, l, env, locals) {
+                Ok(node @ Node::Const(_)) => Ok(node),
+                Ok(node) => Ok(match pos {
+                    Some(pos) => Node::Pos(Rc::from(pos), Box::new(node)),
+                    None => node,
+                }),
+                Err(e) => Ok(Node::Throw(e.unwind(&Nil, pos))),
+            }
+        }
+        Vector(v, _) => constant(
+            analyze_all(v.iter(), env, locals)?,
+            |xs| Ok(vector!(xs)),
+            Node::Vector,
+        ),
+        Hash(hm, _) => constant(
+            analyze_all(hm.iter().flat_map(|(k, v)| vec![k, v]), env, locals)?,
+            hash_map,
+            Node::Hash,
+        ),
+        Set(s, _) => constant(analyze_all(s.iter(), env, locals)?, hash_set, Node::Set),
+        // a macro may expand to a lazy seq, which is a call all the same
+        LazySeq(_) => analyze(&list!(ast.realize()?), env, locals),
+        _ => Ok(Node::Const(ast.clone())),
+    }
+}
+
+fn analyze_all<'a>(
+    forms: impl Iterator<Item = &'a MalVal>,
+    env: &Env,
+    locals: &Locals,
+) -> Result<Vec<Node>, MalErr> {
+    forms.map(|f| analyze(f, env, locals)).collect()
+}
+
+// A collection literal whose elements are all constants is one itself
+fn constant(
+    nodes: Vec<Node>,
+    build: fn(MalArgs) -> MalRet,
+    node: fn(Vec<Node>) -> Node,
+) -> Result<Node, MalErr> {
+    let vals: Option<MalArgs> = nodes
+        .iter()
+        .map(|n| match n {
+            Node::Const(v) => Some(v.clone()),
+            _ => None,
+        })
+        .collect();
+    match vals {
+        Some(vals) => Ok(Node::Const(build(vals)?)),
+        None => Ok(node(nodes)),
+    }
+}
+
+fn analyze_list(ast: &MalVal, l: &MalList, env: &Env, locals: &Locals) -> Result<Node, MalErr> {
+    if !matches!(l.head(), Some(Sym(s)) if resolve(locals, s).is_some()) {
+        if let Some((mf, args)) = is_macro_call(ast, env) {
+            return analyze(&mf.apply(args)?, env, locals);
+        }
+    }
+    let l: Vec<MalVal> = l.iter().cloned().collect();
+    let a0 = match l[0] {
+        Sym(ref s) => Some(s.id()),
+        _ => None,
+    };
+    let args = &l[1..];
+    Ok(match a0 {
+        Some(Symbol::DEF) => match args {
+            [a1 @ Sym(s), a2] => {
+                let v = Box::new(analyze(a2, env, locals)?);
+                match resolve(locals, s) {
+                    Some((depth, slot)) => Node::DefLocal(depth, slot, a1.clone(), v),
+                    None => Node::Def(a1.clone(), v),
+                }
+            }
+            _ => return syntax_error("def!", "(def! symbol value)"),
+        },
+        Some(Symbol::LET) => {
+            let (inits, body) = analyze_bindings("let*", args, env, locals)?;
+            Node::Let(inits, Box::new(body))
+        }
+        Some(Symbol::LOOP) => {
+            let (inits, body) = analyze_bindings("loop*", args, env, locals)?;
+            Node::Loop(inits, Box::new(body))
+        }
+        Some(Symbol::RECUR) => Node::Recur(analyze_all(args.iter(), env, locals)?),
+        Some(Symbol::QUOTE) => match args {
+            [a1] => Node::Const(strip_pos(a1)),
+            _ => return syntax_error("quote", "(quote form)"),
+        },
+        Some(Symbol::QUASIQUOTEEXPAND) => match args {
+            [a1] => Node::Const(strip_pos(&quasiquote(a1))),
+            _ => return syntax_error("quasiquoteexpand", "(quasiquoteexpand form)"),
+        },
+        Some(Symbol::QUASIQUOTE) => match args {
+            [a1] => return analyze(&quasiquote(a1), env, locals),
+            _ => return syntax_error("quasiquote", "(quasiquote form)"),
+        },
+        Some(Symbol::DEFMACRO) => match args {
+            [a1 @ Sym(_), a2] => Node::DefMacro(a1.clone(), Box::new(analyze(a2, env, locals)?)),
+            _ => return syntax_error("defmacro!", "(defmacro! symbol fn)"),
+        },
+        Some(Symbol::MACROEXPAND) => match args {
+            [a1] => Node::Macroexpand(a1.clone()),
+            _ => return syntax_error("macroexpand", "(macroexpand form)"),
+        },
+        Some(Symbol::TRY) => analyze_try(args, env, locals)?,
+        Some(Symbol::LAZY_SEQ) => {
+            Node::LazySeq(Rc::new(Node::Do(analyze_all(args.iter(), env, locals)?)))
+        }
+        Some(Symbol::DO) => Node::Do(analyze_all(args.iter(), env, locals)?),
+        Some(Symbol::IF) => {
+            if args.len() < 2 || args.len() > 3 {
+                return syntax_error("if", "(if test then else?)");
             }
-            Ok(list!(lst))
+            let test = analyze(&args[0], env, locals)?;
+            let then = analyze(&args[1], env, locals)?;
+            let otherwise = match args.get(2) {
+                Some(a3) => analyze(a3, env, locals)?,
+                None => Node::Const(Nil),
+            };
+            Node::If(Box::new(test), Box::new(then), Box::new(otherwise))
         }
-        Vector(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+        Some(Symbol::FN) => match args {
+            [a1 @ List(..), a2] | [a1 @ Vector(..), a2]
+                if a1.elems().unwrap().all(|p| matches!(p, Sym(_))) =>
+            {
+                Node::Fn(Rc::new(FnCode::new(a1, a2, env, locals)?))
             }
-            Ok(vector!(lst))
+            _ => return syntax_error("fn*", "(fn* [symbol ...] body)"),
+        },
+        Some(Symbol::EVAL) => match args {
+            [a1] => Node::Eval(Box::new(analyze(a1, env, locals)?)),
+            _ => return syntax_error("eval", "(eval form)"),
+        },
+        _ => Node::Call(
+            Box::new(analyze(&l[0], env, locals)?),
+            analyze_all(args.iter(), env, locals)?,
+        ),
+    })
+}
+
+// (let* [symbol value ...] body), and loop* alike: the inits, each in a
+// scope with the symbols before it, and the body
+fn analyze_bindings(
+    form: &str,
+    args: &[MalVal],
+    env: &Env,
+    locals: &Locals,
+) -> Result<(Vec<Node>, Node), MalErr> {
+    let (binds, body) = match args {
+        [binds @ List(..), body] | [binds @ Vector(..), body]
+            if binds.elems().unwrap().len() % 2 == 0 =>
+        {
+            (binds.elems().unwrap(), body)
         }
-        Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-            for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        _ => return syntax_error(form, &format!("({} [symbol value ...] body)", form)),
+    };
+    let scope = new_scope(vec![], locals, bound(locals));
+    let inner = Some(scope.clone());
+    let mut nodes = vec![];
+    for (b, e) in binds.tuples() {
+        match b {
+            Sym(s) => {
+                nodes.push(analyze(e, env, &inner)?);
+                scope.names.borrow_mut().push(s.clone());
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            _ => return syntax_error(form, "a symbol in binding position"),
         }
-        _ => Ok(ast.clone()),
     }
+    Ok((nodes, analyze(body, env, &inner)?))
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
-    let ret: MalRet;
-
-    'tco: loop {
-        ret = match ast.clone() {
-            List(l, _) => {
-                if l.len() == 0 {
-                    return Ok(ast);
+// (try* expr (catch* selector? symbol body ...) ... (finally* body ...)?)
+fn analyze_try(l: &[MalVal], env: &Env, locals: &Locals) -> Result<Node, MalErr> {
+    let expected = "(try* expr (catch* selector? symbol body) ... (finally* body)?)";
+    let (expr, clauses) = match l.split_first() {
+        Some(split) => split,
+        None => return syntax_error("try*", expected),
+    };
+    let (clauses, finally) = match clauses.split_last() {
+        Some((List(f, _), rest)) if matches!(f.head(), Some(Sym(s)) if s.id() == Symbol::FINALLY) => {
+            (rest, f.skip(1))
+        }
+        _ => (clauses, MalList::new()),
+    };
+    let mut catches: Vec<Catch> = vec![];
+    for c in clauses {
+        let (sel, b, body) = match c {
+            List(c, _) => match &c.iter().cloned().collect::<Vec<_>>()[..] {
+                [Sym(ref cs), Sym(b), body] if cs.id() == Symbol::CATCH => {
+                    (None, b.clone(), vec![body.clone()])
                 }
-                match macroexpand(ast.clone(), &env) {
-                    (true, Ok(new_ast)) => {
-                        ast = new_ast;
-                        continue 'tco;
-                    }
-                    (_, Err(e)) => return Err(e),
-                    _ => (),
+                [Sym(ref cs), sel, Sym(b), body @ ..]
+                    if cs.id() == Symbol::CATCH && !body.is_empty() =>
+                {
+                    (Some(sel.clone()), b.clone(), body.to_vec())
                 }
+                _ => return syntax_error("try*", expected),
+            },
+            _ => return syntax_error("try*", expected),
+        };
+        let sel = match sel {
+            Some(sel) => Some(analyze(&sel, env, locals)?),
+            None => None,
+        };
+        let inner = Some(new_scope(vec![b], locals, bound(locals)));
+        let body = analyze_all(body.iter(), env, &inner)?;
+        catches.push((sel, Node::Do(body)));
+    }
+    let expr = analyze(expr, env, locals)?;
+    let finally = analyze_all(finally.iter(), env, locals)?;
+    Ok(Node::Try(Box::new(expr), catches, finally))
+}
 
-                if l.len() == 0 {
-                    return Ok(ast);
-                }
-                let a0 = &l[0];
-                match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
-                    }
-                    Sym(ref a0sym) if a0sym == "let*" => {
-                        env = env_new(Some(env.clone()));
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return error("let* with non-List bindings");
-                            }
-                        };
-                        ast = a2;
-                        continue 'tco;
-                    }
-                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
-                    Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
-                        continue 'tco;
-                    }
-                    Sym(ref a0sym) if a0sym == "defmacro!" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        let r = eval(a2, env.clone())?;
-                        match r {
-                            MalFunc {
-                                eval,
-                                ast,
-                                env,
-                                params,
-                                ..
-                            } => Ok(env_set(
-                                &env,
-                                a1.clone(),
-                                MalFunc {
-                                    eval: eval,
-                                    ast: ast.clone(),
-                                    env: env.clone(),
-                                    params: params.clone(),
-                                    is_macro: true,
-                                    meta: Rc::new(Nil),
-                                },
-                            )?),
-                            _ => error("set_macro on non-function"),
-                        }
-                    }
-                    Sym(ref a0sym) if a0sym == "macroexpand" => {
-                        match macroexpand(l[1].clone(), &env) {
-                            (_, Ok(new_ast)) => Ok(new_ast),
-                            (_, e) => return e,
-                        }
-                    }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                }
-                                _ => error("invalid catch block"),
-                            }
-                        }
-                        res => res,
-                    },
-                    Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
-                            List(_, _) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
-                        }
-                    }
-                    Sym(ref a0sym) if a0sym == "if" => {
-                        let cond = eval(l[1].clone(), env.clone())?;
-                        match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
-                                ast = l[3].clone();
-                                continue 'tco;
-                            }
-                            Bool(false) | Nil => Ok(Nil),
-                            _ if l.len() >= 3 => {
-                                ast = l[2].clone();
-                                continue 'tco;
-                            }
-                            _ => Ok(Nil),
+// recur has to be the last thing a loop* body does, and give a value for
+// each of its bindings. Checked once a top-level form or fn* body has been
+// analyzed, before any of it runs; arity is that of the innermost loop*.
+fn check_recur(node: &Node, arity: Option<usize>, tail: bool) -> Result<(), MalErr> {
+    let inner = |n: &Node| check_recur(n, arity, false);
+    match node {
+        Node::Recur(args) => {
+            recur_fits(arity, tail, args.len())?;
+            args.iter().try_for_each(inner)
+        }
+        Node::Loop(inits, body) => {
+            inits.iter().try_for_each(inner)?;
+            check_recur(body, Some(inits.len()), true)
+        }
+        Node::Let(inits, body) => {
+            inits.iter().try_for_each(inner)?;
+            check_recur(body, arity, tail)
+        }
+        Node::Do(nodes) => match nodes.split_last() {
+            Some((last, init)) => {
+                init.iter().try_for_each(inner)?;
+                check_recur(last, arity, tail)
+            }
+            None => Ok(()),
+        },
+        Node::If(test, then, otherwise) => {
+            inner(test)?;
+            check_recur(then, arity, tail)?;
+            check_recur(otherwise, arity, tail)
+        }
+        Node::Vector(xs) | Node::Hash(xs) | Node::Set(xs) => xs.iter().try_for_each(inner),
+        Node::Def(_, n) | Node::DefLocal(_, _, _, n) | Node::DefMacro(_, n) | Node::Eval(n) => {
+            inner(n)
+        }
+        Node::Call(f, args) => {
+            inner(f)?;
+            args.iter().try_for_each(inner)
+        }
+        // recur may not leave a try*
+        Node::Try(expr, catches, finally) => {
+            inner(expr)?;
+            for (sel, body) in catches {
+                sel.iter().try_for_each(inner)?;
+                inner(body)?;
+            }
+            finally.iter().try_for_each(inner)
+        }
+        // the body runs once the loop* it is in may be long done
+        Node::LazySeq(body) => check_recur(body, None, false),
+        Node::Pos(pos, n) => {
+            check_recur(n, arity, tail).map_err(|e| e.unwind(&Nil, Some(pos.to_string())))
+        }
+        Node::Const(_)
+        | Node::Var(_)
+        | Node::Local(..)
+        | Node::Late(..)
+        | Node::Fn(_)
+        | Node::Macroexpand(_)
+        | Node::Throw(_) => Ok(()),
+    }
+}
+
+fn recur_fits(arity: Option<usize>, tail: bool, args: usize) -> Result<(), MalErr> {
+    match arity {
+        Some(n) if tail && n != args => {
+            let values = if n == 1 { "value" } else { "values" };
+            syntax_error("recur", &format!("{} {}, one per loop* binding", n, values))
+        }
+        Some(_) if tail => Ok(()),
+        _ => syntax_error("recur", "(recur expr ...) in tail position of loop*"),
+    }
+}
+
+// check_recur for a fn* body before it is analyzed, on its forms, so that
+// a misplaced recur is an error as soon as the fn* form is analyzed while
+// its macros are still only expanded on the first call. Lists that may be
+// macro calls are left to check_recur then; the rest are calls, or special
+// forms with malformed ones left to analysis.
+fn check_recur_form(
+    form: &MalVal,
+    arity: Option<usize>,
+    tail: bool,
+    env: &Env,
+    locals: &Locals,
+) -> Result<(), MalErr> {
+    let inner = |f: &MalVal| check_recur_form(f, arity, false, env, locals);
+    let l = match form {
+        List(l, _) if !l.is_empty() => l,
+        Vector(v, _) => return v.iter().try_for_each(inner),
+        Hash(hm, _) => return hm.iter().try_for_each(|(k, v)| inner(k).and(inner(v))),
+        Set(s, _) => return s.iter().try_for_each(inner),
+        _ => return Ok(()),
+    };
+    let args: Vec<MalVal> = l.iter().skip(1).cloned().collect();
+    let checked = match l.head() {
+        Some(Sym(s)) => match s.id() {
+            Symbol::RECUR => {
+                recur_fits(arity, tail, args.len())?;
+                args.iter().try_for_each(inner)
+            }
+            Symbol::LET | Symbol::LOOP => match &args[..] {
+                [binds @ List(..), body] | [binds @ Vector(..), body] => {
+                    binds
+                        .elems()
+                        .unwrap()
+                        .skip(1)
+                        .step_by(2)
+                        .try_for_each(inner)?;
+                    match s.id() {
+                        Symbol::LOOP => {
+                            let n = binds.elems().unwrap().len() / 2;
+                            check_recur_form(body, Some(n), true, env, locals)
                         }
+                        _ => check_recur_form(body, arity, tail, env, locals),
                     }
-                    Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        Ok(MalFunc {
-                            eval: eval,
-                            ast: Rc::new(a2),
-                            env: env,
-                            params: Rc::new(a1),
-                            is_macro: false,
-                            meta: Rc::new(Nil),
-                        })
-                    }
-                    Sym(ref a0sym) if a0sym == "eval" => {
-                        ast = eval(l[1].clone(), env.clone())?;
-                        while let Some(ref e) = env.clone().outer {
-                            env = e.clone();
-                        }
-                        continue 'tco;
+                }
+                _ => Ok(()),
+            },
+            Symbol::DO => match args.split_last() {
+                Some((last, init)) => {
+                    init.iter().try_for_each(inner)?;
+                    check_recur_form(last, arity, tail, env, locals)
+                }
+                None => Ok(()),
+            },
+            Symbol::IF => match args.split_first() {
+                Some((test, branches)) => {
+                    inner(test)?;
+                    branches
+                        .iter()
+                        .try_for_each(|b| check_recur_form(b, arity, tail, env, locals))
+                }
+                None => Ok(()),
+            },
+            Symbol::FN => match &args[..] {
+                [_, body] => check_recur_form(body, None, true, env, locals),
+                _ => Ok(()),
+            },
+            // recur may not leave a try*, nor a catch* or finally* in it
+            Symbol::TRY => args.iter().try_for_each(|a| match a {
+                List(c, _)
+                    if matches!(c.head(), Some(Sym(s))
+                        if s.id() == Symbol::CATCH || s.id() == Symbol::FINALLY) =>
+                {
+                    c.iter().skip(1).try_for_each(inner)
+                }
+                a => inner(a),
+            }),
+            Symbol::LAZY_SEQ => args
+                .iter()
+                .try_for_each(|a| check_recur_form(a, None, false, env, locals)),
+            Symbol::DEF | Symbol::DEFMACRO | Symbol::EVAL => args.iter().try_for_each(inner),
+            Symbol::QUOTE | Symbol::QUASIQUOTE | Symbol::QUASIQUOTEEXPAND | Symbol::MACROEXPAND => {
+                Ok(())
+            }
+            _ if resolve(locals, s).is_some() => args.iter().try_for_each(inner),
+            _ => match env_find(env, s).map(|e| env_get(&e, &l[0])) {
+                Some(Ok(MalFunc { is_macro: true, .. })) | None => Ok(()),
+                _ => args.iter().try_for_each(inner),
+            },
+        },
+        _ => l.iter().try_for_each(inner),
+    };
+    checked.map_err(|e| e.unwind(&Nil, source_pos(form)))
+}
+
+// What exec leaves for run to do: nothing more than return a value, make
+// a call in tail position, from the form at the position given, or, for
+// the loop* the node is in, run its body again with new bindings
+enum Tail {
+    Value(MalVal),
+    Call(MalVal, MalArgs, Option<Rc<str>>),
+    Recur(MalArgs),
+}
+
+// Each mal call that is not in tail position costs a run frame on the
+// Rust stack, with exec inlined into it, so exec loops through the forms
+// that end in another instead of recursing, and keeps what it rarely
+// needs in other functions to stay small.
+#[inline(always)]
+fn exec(node: &Node, env: &Env) -> Result<Tail, MalErr> {
+    let (mut node, mut env) = (node, env.clone());
+    // the innermost position passed through, claimed by a tail call or
+    // an error left unclaimed by anything inside it
+    let mut pos: Option<&Rc<str>> = None;
+    let res = loop {
+        break Ok(Tail::Value(match node {
+            Node::Let(inits, body) => match bind_inits(inits, &env) {
+                Ok(e) => {
+                    (node, env) = (body, e);
+                    continue;
+                }
+                Err(e) => break Err(e),
+            },
+            Node::Do(nodes) => match nodes.split_last() {
+                Some((last, init)) => {
+                    if let Err(e) = init.iter().try_for_each(|n| run(n, &env).map(|_| ())) {
+                        break Err(e);
                     }
-                    _ => match eval_ast(&ast, &env)? {
-                        List(ref el, _) => {
-                            let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
-                            match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
-                                    continue 'tco;
-                                }
-                                _ => error("attempt to call non-function"),
-                            }
-                        }
-                        _ => error("expected a list"),
-                    },
+                    node = last;
+                    continue;
                 }
+                None => Nil,
+            },
+            Node::If(test, then, otherwise) => {
+                node = match value(test, &env) {
+                    Ok(Bool(false) | Nil) => otherwise,
+                    Ok(_) => then,
+                    Err(e) => break Err(e),
+                };
+                continue;
+            }
+            Node::Pos(p, inner) => {
+                pos = pos.or(Some(p));
+                node = inner;
+                continue;
+            }
+            Node::Call(f, args) => {
+                let call = value(f, &env).and_then(|f| {
+                    let mut vals = Vec::with_capacity(args.len());
+                    for a in args {
+                        vals.push(value(a, &env)?);
+                    }
+                    Ok(Tail::Call(f, vals, pos.cloned()))
+                });
+                break call;
+            }
+            Node::Loop(inits, body) => break exec_loop(inits, body, &env),
+            Node::Recur(args) => break run_all(args, &env).map(Tail::Recur),
+            Node::Throw(e) => break Err(e.clone()),
+            _ => match value_of(node, &env) {
+                Ok(v) => v,
+                Err(e) => break Err(e),
+            },
+        }));
+    };
+    match (res, pos) {
+        (Err(e), Some(p)) => Err(e.unwind(&Nil, Some(p.to_string()))),
+        (res, _) => res,
+    }
+}
+
+// run, without a frame of its own for the nodes that make no calls
+#[inline(always)]
+fn value(node: &Node, env: &Env) -> MalRet {
+    match node {
+        Node::Const(v) => Ok(v.clone()),
+        Node::Local(depth, slot, s) => env_get_slot(env, *depth, *slot, s),
+        Node::Late(depth, slot, s, before) => {
+            env_get_slot(env, *depth, *slot, s).or_else(|_| value(before, env))
+        }
+        Node::Var(s) => env_get(env, s),
+        _ => run(node, env),
+    }
+}
+
+// The env a let* or loop* body runs in, with its inits bound in order
+fn bind_inits(inits: &[Node], env: &Env) -> Result<Env, MalErr> {
+    let env = env_frame(Some(env.clone()), Vec::with_capacity(inits.len()));
+    for e in inits {
+        let v = value(e, &env)?;
+        env_push(&env, v);
+    }
+    Ok(env)
+}
+
+#[inline(never)]
+fn exec_loop(inits: &[Node], body: &Node, env: &Env) -> Result<Tail, MalErr> {
+    let mut env = bind_inits(inits, env)?;
+    // each pass binds in a new env, so closures made in one keep its values
+    loop {
+        match exec(body, &env)? {
+            Tail::Recur(vals) => env = env_frame(env.outer.clone(), vals),
+            tail => return Ok(tail),
+        }
+    }
+}
+
+// The nodes exec has nothing to loop through or call for
+#[inline(never)]
+fn value_of(node: &Node, env: &Env) -> MalRet {
+    Ok(match node {
+        Node::Const(v) => v.clone(),
+        Node::Var(s) => env_get(env, s)?,
+        Node::Local(depth, slot, s) => env_get_slot(env, *depth, *slot, s)?,
+        Node::Late(depth, slot, s, before) => match env_get_slot(env, *depth, *slot, s) {
+            Ok(v) => v,
+            Err(_) => value(before, env)?,
+        },
+        Node::Vector(xs) => vector!(run_all(xs, env)?),
+        Node::Hash(kvs) => hash_map(run_all(kvs, env)?)?,
+        Node::Set(xs) => hash_set(run_all(xs, env)?)?,
+        Node::Def(a1, a2) => define(env, a1, run(a2, env)?)?,
+        Node::DefLocal(depth, slot, a1, a2) => define_local(env, *depth, *slot, a1, run(a2, env)?)?,
+        Node::DefMacro(a1, a2) => define_macro(env, a1, run(a2, env)?)?,
+        Node::Fn(code) => MalFunc {
+            eval: run_body,
+            ast: code.body.clone(),
+            env: env.clone(),
+            params: code.params.clone(),
+            is_macro: false,
+            name: None,
+            meta: Rc::new(Nil),
+            code: Some(code.clone()),
+        },
+        Node::Macroexpand(a1) => macroexpand(a1.clone(), env).1?,
+        Node::Try(expr, catches, finally) => {
+            let res = match run(expr, env).and_then(MalVal::force_first) {
+                Err(e) => catch(e, catches, env, run),
+                res => res,
+            };
+            for n in finally {
+                run(n, env)?;
+            }
+            res?
+        }
+        Node::LazySeq(body) => {
+            let (body, env) = (body.clone(), env.clone());
+            lazy_seq(move || run(&body, &env))
+        }
+        Node::Eval(a1) => {
+            let ast = run(a1, env)?;
+            let mut env = env.clone();
+            while let Some(ref e) = env.clone().outer {
+                env = e.clone();
+            }
+            eval(ast, env)?
+        }
+        // exec handles the rest
+        _ => unreachable!(),
+    })
+}
+
+fn define(env: &Env, a1: &MalVal, v: MalVal) -> MalRet {
+    env_set(env, a1.clone(), v.named(a1))
+}
+
+fn define_local(env: &Env, depth: usize, slot: usize, a1: &MalVal, v: MalVal) -> MalRet {
+    env_set_slot(env, depth, slot, a1, v.named(a1))
+}
+
+fn define_macro(env: &Env, a1: &MalVal, v: MalVal) -> MalRet {
+    match v {
+        MalFunc {
+            eval,
+            ast,
+            env: fenv,
+            params,
+            name,
+            code,
+            ..
+        } => env_set(
+            env,
+            a1.clone(),
+            MalFunc {
+                eval,
+                ast,
+                env: fenv,
+                params,
+                is_macro: true,
+                name,
+                meta: Rc::new(Nil),
+                code,
             }
-            _ => eval_ast(&ast, &env),
+            .named(a1),
+        ),
+        _ => error("set_macro on non-function"),
+    }
+}
+
+// Evaluate node, making the calls it ends in without growing the stack.
+// Errors leaving it record the function running after the last tail call
+// and where the first one was made, for the stack trace.
+fn run(node: &Node, env: &Env) -> MalRet {
+    let mut func = Nil;
+    let mut caller: Option<Rc<str>> = None;
+    // the body and env of the function last tail called, so that exec is
+    // inlined in one place only
+    let mut held: Option<(Rc<Node>, Env)> = None;
+    let fail = |e: MalErr, func: &MalVal, caller: Option<Rc<str>>| {
+        Err(e
+            .unwind(func, None)
+            .unwind(&Nil, caller.map(|p| p.to_string())))
+    };
+    loop {
+        let step = match &held {
+            Some((node, env)) => exec(node, env),
+            None => exec(node, env),
+        };
+        let (f, args, pos) = match step {
+            Ok(Tail::Value(v)) => return Ok(v),
+            Ok(Tail::Call(f, args, pos)) => (f, args, pos),
+            // check_recur keeps it from getting here
+            Ok(Tail::Recur(_)) => return syntax_error("recur", "a loop* around it"),
+            Err(e) => return fail(e, &func, caller),
         };
+        let at = |e: MalErr| e.unwind(&Nil, pos.as_ref().map(|p| p.to_string()));
+        match fn_code(&f) {
+            Some((code, fenv)) => {
+                let env = match env_bind_slots(Some(fenv), &code.params, args, f.fn_name()) {
+                    Ok(env) => env,
+                    Err(e) => return fail(at(e), &func, caller),
+                };
+                if let Nil = func {
+                    caller = pos;
+                }
+                func = f;
+                match code.node(&env) {
+                    Ok(node) => held = Some((node, env)),
+                    Err(e) => return fail(e, &func, caller),
+                }
+            }
+            None => {
+                return match f.apply(args) {
+                    Ok(v) => Ok(v),
+                    Err(e) => fail(at(e), &func, caller),
+                }
+            }
+        }
+    }
+}
+
+fn run_all(nodes: &[Node], env: &Env) -> Result<MalArgs, MalErr> {
+    nodes.iter().map(|n| value(n, env)).collect()
+}
+
+// What MalVal::apply runs for the functions made here, in an env binding
+// their params
+fn run_body(f: MalVal, env: Env) -> MalRet {
+    match fn_code(&f) {
+        Some((code, _)) => run(&*code.node(&env)?, &env),
+        None => type_error("attempt to call non-function"),
+    }
+}
+
+thread_local! {
+    // set by --vm: run forms compiled to bytecode instead of walking them
+    static VM: Cell<bool> = const { Cell::new(false) };
+}
+
+// Analyze a form and run it. The forms of a top-level do are analyzed one
+// at a time, so that those in a file read by load-file can use macros
+// defined before them.
+fn eval(ast: MalVal, env: Env) -> MalRet {
+    if let List(l, _) = &ast {
+        if matches!(l.head(), Some(Sym(s)) if s.id() == Symbol::DO) {
+            let mut ret = Nil;
+            for form in l.iter().skip(1) {
+                ret = eval(form.clone(), env.clone())?;
+            }
+            return Ok(ret);
+        }
+    }
+    let node = analyze(&ast, &env, &None)?;
+    check_recur(&node, None, true)?;
+    if VM.with(Cell::get) {
+        vm::run(&vm::compile(&node), &env)
+    } else {
+        run(&node, &env)
+    }
+}
 
-        break;
-    } // end 'tco loop
+// Run the first handler whose selector matches, rethrow if none does
+fn catch<C>(e: MalErr, catches: &[Catch<C>], env: &Env, run: fn(&C, &Env) -> MalRet) -> MalRet {
+    for (sel, body) in catches {
+        let exc = error_value(&e, sel.is_some());
+        if let Some(sel) = sel {
+            if !selects(&run(sel, env)?, &exc)? {
+                continue;
+            }
+        }
+        let catch_env = env_frame(Some(env.clone()), vec![exc.clone()]);
+        CAUGHT.with(|c| c.borrow_mut().push((exc.clone(), error_trace(&e))));
+        let res = run(body, &catch_env);
+        CAUGHT.with(|c| c.borrow_mut().pop());
+        return res;
+    }
+    Err(e)
+}
 
-    ret
+// A keyword selects values whose :type, or :type in their ex-data, is that
+// keyword, anything else is a predicate called on the caught value
+fn selects(sel: &MalVal, exc: &MalVal) -> Result<bool, MalErr> {
+    if !matches!(sel, Keyword(_)) {
+        return Ok(!matches!(sel.apply(vec![exc.clone()])?, Bool(false) | Nil));
+    }
+    let type_of = |v: &MalVal| match v {
+        Hash(hm, _) => hm.get(&keyword("type")).cloned(),
+        _ => None,
+    };
+    let data = match exc {
+        Hash(hm, _) => hm.get(&keyword("data")).cloned(),
+        _ => None,
+    };
+    Ok(type_of(exc).as_ref() == Some(sel) || data.and_then(|d| type_of(&d)).as_ref() == Some(sel))
 }
 
 // print
@@ -322,12 +1119,17 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     let ast = read(str)?;
     let exp = eval(ast, env.clone())?;
+    exp.realize_printed()?;
     Ok(print(&exp))
 }
 
 fn main() {
-    let mut args = std::env::args();
-    let arg1 = args.nth(1);
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("--vm") {
+        args.next();
+        VM.with(|vm| vm.set(true));
+    }
+    let arg1 = args.next();
 
     // `()` can be used when no completer is required
     let mut rl = Editor::<()>::new();
@@ -340,15 +1142,23 @@ fn main() {
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(
+        &repl_env,
+        "stack-trace",
+        func("stack-trace", 1, Some(1), stack_trace),
+    );
+    env_sets(
+        &repl_env,
+        "*ARGV*",
+        list!(args.map(Str).collect::<MalList>()),
+    );
 
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
+    let _ = rep("(def! *print-length* nil)", &repl_env);
+    set_print_env(&repl_env);
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-    let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
-        &repl_env,
-    );
+    let _ = rep("(def! load-file (fn* (f) (eval (read-file f))))", &repl_env);
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
 
     // Invoked with arguments
@@ -1,64 +1,355 @@
+use std::any::Any;
 use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash as StdHash, Hasher};
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHashSet, FnvHasher};
 use itertools::Itertools;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::ToPrimitive;
 
-use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::env::{env_bind, env_bind_slots, Env};
+use crate::printer::print_length;
+use crate::types::MalErr::{ErrMalVal, ErrString, ErrTrace, ErrTyped};
+use crate::types::MalVal::{
+    Atom, Big, Bool, Char, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio,
+    Reduced, Regex, Set, Str, Sym, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    Big(Rc<BigInt>),
+    Ratio(Rc<BigRational>),
+    Float(f64),
+    Char(char),
     Str(String),
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    // always built by symbol() or Symbol::new(), so equal symbols share an id
+    Sym(Symbol),
+    // always built by keyword(), so equal keywords share the same Rc
+    Keyword(Rc<str>),
+    List(MalList, Rc<MalVal>),
+    // boxed, so that MalVal stays the size of its other variants
+    Vector(Rc<MalVec>, Rc<MalVal>),
+    Hash(MalMap, Rc<MalVal>),
+    Set(MalSet, Rc<MalVal>),
+    Func(Builtin, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>,
         env: Env,
         params: Rc<MalVal>,
         is_macro: bool,
+        name: Option<Rc<str>>,
         meta: Rc<MalVal>,
+        // the body as analyzed by a step that analyzes code ahead of
+        // running it; eval is then handed the function instead of its ast,
+        // and the params are bound in slots
+        code: Option<Rc<dyn Any>>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    Regex(Rc<regex::Regex>),
+    LazySeq(Rc<RefCell<LazyCell>>),
+    // (reduced x): tells reduce and transduce to stop with x
+    Reduced(Rc<MalVal>),
 }
 
-#[derive(Debug)]
+// A lazy seq starts out as a thunk and is replaced by its first cell once
+// realized. The thunk returns a seq, whose first cell becomes this one's.
+pub enum LazyCell {
+    Thunk(Rc<dyn Fn() -> MalRet>),
+    Empty,
+    Cons(MalVal, MalVal),
+}
+
+// Dropping a long realized seq cell by cell would recurse once per cell,
+// so the chain is unlinked in a loop, as far as nothing else shares it
+impl Drop for LazyCell {
+    fn drop(&mut self) {
+        let mut next = match self {
+            LazyCell::Cons(_, r) => std::mem::replace(r, Nil),
+            _ => return,
+        };
+        while let LazySeq(cell) = next {
+            let mut cell = match Rc::try_unwrap(cell) {
+                Ok(cell) => cell.into_inner(),
+                Err(_) => return,
+            };
+            next = match cell {
+                LazyCell::Cons(_, ref mut r) => std::mem::replace(r, Nil),
+                _ => return,
+            };
+        }
+    }
+}
+
+impl fmt::Debug for LazyCell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LazyCell::Thunk(_) => write!(f, "Thunk"),
+            LazyCell::Empty => write!(f, "Empty"),
+            LazyCell::Cons(x, r) => write!(f, "Cons({:?}, {:?})", x, r),
+        }
+    }
+}
+
+// f is a closure so builtins can make functions with state of their own,
+// as transducers do
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub f: Rc<dyn Fn(MalArgs) -> MalRet>,
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Builtin({})", self.name)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum MalErr {
     ErrString(String),
+    // an interpreter error catch* can tell apart by its :type, which
+    // only the steps binding errors as maps read
+    #[allow(dead_code)]
+    ErrTyped(&'static str, String),
     ErrMalVal(MalVal),
+    // an error with the mal call stack it unwound on the way out
+    ErrTrace(Box<MalErr>, Trace),
+}
+
+// Frames are "name (file:line:column)" strings, innermost first
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub frames: Vec<String>,
+    // position not yet claimed by an enclosing function's frame
+    pub pos: Option<String>,
 }
 
 pub type MalArgs = Vec<MalVal>;
+// Persistent collections: clones are O(1) and conj/cons/assoc/rest share
+// structure with the original instead of copying it. Lists are cons cells,
+// vectors RRB trees and maps HAMTs.
+pub type MalVec = im_rc::Vector<MalVal>;
+pub type MalMap = im_rc::HashMap<MalVal, MalVal, FnvBuildHasher>;
+pub type MalSet = im_rc::HashSet<MalVal, FnvBuildHasher>;
 pub type MalRet = Result<MalVal, MalErr>;
+pub type MalFn = fn(MalArgs) -> MalRet;
+
+// A list of cons cells, each knowing the length of the list it starts
+#[derive(Clone, Default)]
+pub struct MalList(Option<Rc<Cons>>);
+
+struct Cons {
+    first: MalVal,
+    rest: MalList,
+    len: usize,
+}
+
+// Dropping a long list cell by cell would recurse once per cell, so the
+// cells nothing else shares are unlinked in a loop instead
+impl Drop for Cons {
+    fn drop(&mut self) {
+        let mut next = self.rest.0.take();
+        while let Some(cell) = next {
+            next = match Rc::try_unwrap(cell) {
+                Ok(mut cell) => cell.rest.0.take(),
+                Err(_) => return,
+            };
+        }
+    }
+}
+
+impl MalList {
+    pub fn new() -> MalList {
+        MalList(None)
+    }
+
+    pub fn cons(&self, x: MalVal) -> MalList {
+        MalList(Some(Rc::new(Cons {
+            first: x,
+            rest: self.clone(),
+            len: self.len() + 1,
+        })))
+    }
+
+    // The elements of xs followed by those of the list, which is shared
+    pub fn prepend(&self, xs: impl ExactSizeIterator<Item = MalVal>) -> MalList {
+        let mut len = xs.len() + self.len();
+        let mut head = MalList::new();
+        let mut cur = &mut head;
+        for x in xs {
+            let cell = Rc::new(Cons {
+                first: x,
+                rest: MalList::new(),
+                len,
+            });
+            len -= 1;
+            *cur = MalList(Some(cell));
+            // the cell was just made, nothing else holds it yet
+            cur = &mut Rc::get_mut(cur.0.as_mut().unwrap()).unwrap().rest;
+        }
+        *cur = self.clone();
+        head
+    }
+
+    pub fn head(&self) -> Option<&MalVal> {
+        self.0.as_ref().map(|c| &c.first)
+    }
+
+    // The list without its first n elements, sharing their tail
+    pub fn skip(&self, n: usize) -> MalList {
+        let mut l = self;
+        for _ in 0..n {
+            match &l.0 {
+                Some(c) => l = &c.rest,
+                None => break,
+            }
+        }
+        l.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map_or(0, |c| c.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&MalVal> {
+        self.iter().nth(i)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        self.iter().last()
+    }
+
+    pub fn iter(&self) -> ListIter<'_> {
+        ListIter(self)
+    }
+}
+
+pub struct ListIter<'a>(&'a MalList);
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a MalVal;
+    fn next(&mut self) -> Option<&'a MalVal> {
+        let c = self.0 .0.as_ref()?;
+        self.0 = &c.rest;
+        Some(&c.first)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl ExactSizeIterator for ListIter<'_> {}
+
+impl<'a> IntoIterator for &'a MalList {
+    type Item = &'a MalVal;
+    type IntoIter = ListIter<'a>;
+    fn into_iter(self) -> ListIter<'a> {
+        self.iter()
+    }
+}
+
+impl std::ops::Index<usize> for MalList {
+    type Output = MalVal;
+    fn index(&self, i: usize) -> &MalVal {
+        self.get(i).expect("list index out of range")
+    }
+}
+
+impl std::iter::FromIterator<MalVal> for MalList {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalList {
+        let xs: Vec<MalVal> = iter.into_iter().collect();
+        MalList::new().prepend(xs.into_iter())
+    }
+}
+
+impl From<Vec<MalVal>> for MalList {
+    fn from(xs: Vec<MalVal>) -> MalList {
+        MalList::new().prepend(xs.into_iter())
+    }
+}
+
+impl From<MalVec> for MalList {
+    fn from(xs: MalVec) -> MalList {
+        MalList::new().prepend(xs.into_iter())
+    }
+}
+
+impl From<&[MalVal]> for MalList {
+    fn from(xs: &[MalVal]) -> MalList {
+        MalList::new().prepend(xs.iter().cloned())
+    }
+}
+
+impl PartialEq for MalList {
+    fn eq(&self, other: &MalList) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for MalList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// The elements of a list or a vector
+pub enum Elems<'a> {
+    List(ListIter<'a>),
+    Vector(im_rc::vector::Iter<'a, MalVal>),
+}
+
+impl<'a> Iterator for Elems<'a> {
+    type Item = &'a MalVal;
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            Elems::List(i) => i.next(),
+            Elems::Vector(i) => i.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            Elems::List(i) => i.size_hint(),
+            Elems::Vector(i) => i.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for Elems<'_> {}
 
 // type utility macros
 
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    List(crate::types::MalList::from($seq),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List(crate::types::MalList::from(v),Rc::new(Nil))
   }}
 }
 
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    Vector(Rc::new(crate::types::MalVec::from($seq)),Rc::new(Nil))
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector(Rc::new(crate::types::MalVec::from(v)),Rc::new(Nil))
   }}
 }
 
@@ -68,10 +359,218 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+pub fn type_error(s: &str) -> MalRet {
+    Err(ErrTyped("wrong-type", s.to_string()))
+}
+
+pub fn arity_error(n: usize, name: &str) -> MalErr {
+    ErrTyped(
+        "arity",
+        format!("Wrong number of args ({}) passed to {}", n, name),
+    )
+}
+
 pub fn format_error(e: MalErr) -> String {
     match e {
-        ErrString(s) => s.clone(),
+        ErrString(s) | ErrTyped(_, s) => s.clone(),
         ErrMalVal(mv) => mv.pr_str(true),
+        ErrTrace(e, trace) => {
+            let mut s = format_error(*e);
+            for frame in trace.frames.iter().chain(trace.pos.iter()) {
+                s.push_str("\n  at ");
+                s.push_str(frame);
+            }
+            s
+        }
+    }
+}
+
+impl MalErr {
+    // Record where the error left func (Nil for code outside of any
+    // function), pos is used unless an inner form already set one
+    pub fn unwind(self, func: &MalVal, pos: Option<String>) -> MalErr {
+        let (e, mut trace) = match self {
+            ErrTrace(e, trace) => (e, trace),
+            e => (Box::new(e), Trace::default()),
+        };
+        let pos = trace.pos.take().or(pos);
+        match (func, pos) {
+            (Nil, pos) => trace.pos = pos,
+            (f, Some(pos)) => trace.frames.push(format!("{} ({})", f.fn_name(), pos)),
+            (f, None) => trace.frames.push(f.fn_name().to_string()),
+        }
+        if trace.frames.is_empty() && trace.pos.is_none() {
+            *e
+        } else {
+            ErrTrace(e, trace)
+        }
+    }
+}
+
+// Integers are only kept as Big when they do not fit an Int
+pub fn int_val(n: BigInt) -> MalVal {
+    match n.to_i64() {
+        Some(i) => Int(i),
+        None => Big(Rc::new(n)),
+    }
+}
+
+// Ratios are kept normalized, whole numbers collapse to integers
+pub fn ratio(r: BigRational) -> MalVal {
+    if r.is_integer() {
+        int_val(r.to_integer())
+    } else {
+        Ratio(Rc::new(r))
+    }
+}
+
+thread_local! {
+    static KEYWORDS: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+}
+
+// Keywords are interned, "ns/kw" is the keyword :ns/kw
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        match kws.get(name) {
+            Some(k) => Keyword(k.clone()),
+            None => {
+                let k: Rc<str> = Rc::from(name);
+                kws.insert(k.clone());
+                Keyword(k)
+            }
+        }
+    })
+}
+
+// Symbols are interned: each name gets an id the first time it is seen, so
+// comparing or hashing a symbol is comparing or hashing its id
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    id: u32,
+    name: Rc<str>,
+}
+
+// The special forms are interned first, in this order, so their ids are
+// known ahead and eval can dispatch on them with a match
+const SPECIAL_FORMS: [&str; 20] = [
+    "def!",
+    "let*",
+    "quote",
+    "quasiquote",
+    "quasiquoteexpand",
+    "unquote",
+    "splice-unquote",
+    "defmacro!",
+    "macroexpand",
+    "try*",
+    "catch*",
+    "finally*",
+    "lazy-seq",
+    "do",
+    "if",
+    "fn*",
+    "eval",
+    "&",
+    "loop*",
+    "recur",
+];
+
+// Only the steps that dispatch on ids read these
+#[allow(dead_code)]
+impl Symbol {
+    pub const DEF: u32 = 0;
+    pub const LET: u32 = 1;
+    pub const QUOTE: u32 = 2;
+    pub const QUASIQUOTE: u32 = 3;
+    pub const QUASIQUOTEEXPAND: u32 = 4;
+    pub const UNQUOTE: u32 = 5;
+    pub const SPLICE_UNQUOTE: u32 = 6;
+    pub const DEFMACRO: u32 = 7;
+    pub const MACROEXPAND: u32 = 8;
+    pub const TRY: u32 = 9;
+    pub const CATCH: u32 = 10;
+    pub const FINALLY: u32 = 11;
+    pub const LAZY_SEQ: u32 = 12;
+    pub const DO: u32 = 13;
+    pub const IF: u32 = 14;
+    pub const FN: u32 = 15;
+    pub const EVAL: u32 = 16;
+    pub const AMP: u32 = 17;
+    pub const LOOP: u32 = 18;
+    pub const RECUR: u32 = 19;
+
+    pub fn new(name: &str) -> Symbol {
+        SYMBOLS.with(|syms| {
+            let mut syms = syms.borrow_mut();
+            match syms.get(name) {
+                Some(s) => s.clone(),
+                None => {
+                    let id = syms.len() as u32;
+                    let name: Rc<str> = Rc::from(name);
+                    let s = Symbol {
+                        id,
+                        name: name.clone(),
+                    };
+                    syms.insert(name, s.clone());
+                    s
+                }
+            }
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<FnvHashMap<Rc<str>, Symbol>> = RefCell::new(
+        SPECIAL_FORMS
+            .iter()
+            .enumerate()
+            .map(|(id, f)| {
+                let name: Rc<str> = Rc::from(*f);
+                (name.clone(), Symbol { id: id as u32, name })
+            })
+            .collect()
+    );
+}
+
+pub fn symbol(name: &str) -> MalVal {
+    Sym(Symbol::new(name))
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl StdHash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)
     }
 }
 
@@ -79,61 +578,245 @@ pub fn atom(mv: &MalVal) -> MalVal {
     Atom(Rc::new(RefCell::new(mv.clone())))
 }
 
+fn not_seq(v: &MalVal) -> MalErr {
+    ErrTyped("wrong-type", format!("{} is not a seq", v.pr_str(true)))
+}
+
+pub fn lazy_seq(f: impl Fn() -> MalRet + 'static) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(LazyCell::Thunk(Rc::new(f)))))
+}
+
+// An already realized cell, rest may be lazy
+pub fn lazy_cons(first: MalVal, rest: MalVal) -> MalVal {
+    LazySeq(Rc::new(RefCell::new(LazyCell::Cons(first, rest))))
+}
+
+// The thunk is called without the cell borrowed, and stays in place if it
+// fails, so forcing again retries it. A thunk returning another unrealized
+// lazy seq is followed in a loop rather than by recursion.
+fn force(cell: &Rc<RefCell<LazyCell>>) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+    let mut thunk = match *cell.borrow() {
+        LazyCell::Thunk(ref f) => f.clone(),
+        LazyCell::Empty => return Ok(None),
+        LazyCell::Cons(ref x, ref r) => return Ok(Some((x.clone(), r.clone()))),
+    };
+    let mut pending = vec![cell.clone()];
+    let first = loop {
+        let v = thunk()?;
+        let next = match v {
+            LazySeq(ref c) => match *c.borrow() {
+                LazyCell::Thunk(ref f) => Some((c.clone(), f.clone())),
+                _ => None,
+            },
+            _ => None,
+        };
+        match next {
+            Some((c, f)) => {
+                pending.push(c);
+                thunk = f;
+            }
+            None => break v.uncons()?,
+        }
+    };
+    for c in pending {
+        *c.borrow_mut() = match first {
+            Some((ref x, ref r)) => LazyCell::Cons(x.clone(), r.clone()),
+            None => LazyCell::Empty,
+        };
+    }
+    Ok(first)
+}
+
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
-            _ => error("invalid type for keyword"),
+            Str(s) => Ok(keyword(s)),
+            Keyword(_) => Ok(self.clone()),
+            _ => type_error("invalid type for keyword"),
+        }
+    }
+
+    // The elements of a list or vector
+    pub fn elems(&self) -> Option<Elems<'_>> {
+        match self {
+            List(l, _) => Some(Elems::List(l.iter())),
+            Vector(v, _) => Some(Elems::Vector(v.iter())),
+            _ => None,
         }
     }
 
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            LazySeq(_) => Ok(Bool(self.uncons()?.is_none())),
+            List(l, _) => Ok(Bool(l.is_empty())),
+            Vector(v, _) => Ok(Bool(v.is_empty())),
+            Set(s, _) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
-            _ => error("invalid type for empty?"),
+            _ => type_error("invalid type for empty?"),
         }
     }
 
     pub fn count(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            List(l, _) => Ok(Int(l.len() as i64)),
+            Vector(v, _) => Ok(Int(v.len() as i64)),
+            Set(s, _) => Ok(Int(s.len() as i64)),
+            Str(s) => Ok(Int(s.chars().count() as i64)),
+            LazySeq(_) => Ok(Int(self.realize()?.len() as i64)),
             Nil => Ok(Int(0)),
-            _ => error("invalid type for count"),
+            _ => type_error("invalid type for count"),
+        }
+    }
+
+    // The elements of a seqable value other than a lazy seq, map entries
+    // as [k v] vectors and strings as chars
+    pub fn seq_elems(&self) -> Option<MalVec> {
+        match self {
+            List(l, _) => Some(l.iter().cloned().collect()),
+            Vector(v, _) => Some((**v).clone()),
+            Hash(hm, _) => Some(
+                hm.iter()
+                    .map(|(k, v)| vector![k.clone(), v.clone()])
+                    .collect(),
+            ),
+            Set(s, _) => Some(s.iter().cloned().collect()),
+            Str(s) => Some(s.chars().map(Char).collect()),
+            Nil => Some(MalVec::new()),
+            _ => None,
+        }
+    }
+
+    // The first element and the rest of a seq, or None when it is empty.
+    // Only the first cell of a lazy seq is realized.
+    pub fn uncons(&self) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+        match self {
+            LazySeq(cell) => force(cell),
+            List(l, _) => Ok(l.head().map(|x| (x.clone(), List(l.skip(1), Rc::new(Nil))))),
+            _ => match self.seq_elems() {
+                Some(l) => Ok(l.front().cloned().map(|x| (x, list!(l.skip(1))))),
+                None => Err(not_seq(self)),
+            },
+        }
+    }
+
+    // The value, with the first element of a lazy seq realized so that an
+    // error computing it is raised now. try* uses it on its body's value.
+    pub fn force_first(self) -> MalRet {
+        if let LazySeq(_) = self {
+            self.uncons()?;
+        }
+        Ok(self)
+    }
+
+    // All the elements of a seq, which for a lazy seq had better be finite
+    pub fn realize(&self) -> Result<MalVec, MalErr> {
+        let mut res = MalVec::new();
+        let mut s = self.clone();
+        while let LazySeq(_) = s {
+            match s.uncons()? {
+                Some((x, r)) => {
+                    res.push_back(x);
+                    s = r;
+                }
+                None => return Ok(res),
+            }
+        }
+        res.append(s.seq_elems().ok_or_else(|| not_seq(&s))?);
+        Ok(res)
+    }
+
+    // Realize as much of the lazy seqs in a value as printing it shows, so
+    // errors raised on the way surface here and not as a truncated seq
+    pub fn realize_printed(&self) -> Result<(), MalErr> {
+        let limit = print_length().unwrap_or(usize::MAX);
+        match self {
+            List(..) | Vector(..) => self
+                .elems()
+                .unwrap()
+                .take(limit)
+                .try_for_each(|x| x.realize_printed()),
+            Hash(hm, _) => hm
+                .iter()
+                .take(limit)
+                .try_for_each(|(k, v)| k.realize_printed().and(v.realize_printed())),
+            Set(s, _) => s.iter().take(limit).try_for_each(|x| x.realize_printed()),
+            LazySeq(_) => {
+                let mut s = self.clone();
+                for _ in 0..limit {
+                    match s.uncons()? {
+                        Some((x, r)) => {
+                            x.realize_printed()?;
+                            s = r;
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                // whether there is more to elide
+                s.uncons().map(|_| ())
+            }
+            _ => Ok(()),
         }
     }
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
+            Func(ref b, _) => b.call(args),
             MalFunc {
                 eval,
                 ref ast,
                 ref env,
                 ref params,
+                ref code,
                 ..
             } => {
                 let a = &**ast;
                 let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
-                Ok(eval(a.clone(), fn_env)?)
+                let res = match code {
+                    Some(_) => {
+                        let fn_env = env_bind_slots(Some(env.clone()), p, args, self.fn_name())?;
+                        eval(self.clone(), fn_env)
+                    }
+                    None => {
+                        let fn_env = env_bind(Some(env.clone()), p.clone(), args, self.fn_name())?;
+                        eval(a.clone(), fn_env)
+                    }
+                };
+                res.map_err(|e| e.unwind(self, None))
             }
-            _ => error("attempt to call non-function"),
+            _ => type_error("attempt to call non-function"),
         }
     }
 
-    pub fn keyword_q(&self) -> bool {
+    // Name used in error messages: a :name in the metadata, else the
+    // symbol the function was first def!'d to
+    pub fn fn_name(&self) -> &str {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
+            MalFunc { meta, name, .. } => match **meta {
+                Hash(ref hm, _) => match hm.get(&keyword("name")) {
+                    Some(Str(s)) => s,
+                    Some(Sym(s)) => s,
+                    _ => name.as_deref().unwrap_or("anonymous fn"),
+                },
+                _ => name.as_deref().unwrap_or("anonymous fn"),
+            },
+            Func(b, _) => b.name,
+            _ => "non-function",
         }
     }
 
+    pub fn named(mut self, sym: &MalVal) -> MalVal {
+        match (&mut self, sym) {
+            (MalFunc { name, .. }, Sym(s)) if name.is_none() => *name = Some(s.name.clone()),
+            _ => (),
+        }
+        self
+    }
+
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
-            _ => error("attempt to deref a non-Atom"),
+            Reduced(v) => Ok((**v).clone()),
+            _ => type_error("attempt to deref a non-Atom"),
         }
     }
 
@@ -143,7 +826,7 @@ impl MalVal {
                 *a.borrow_mut() = new.clone();
                 Ok(new.clone())
             }
-            _ => error("attempt to reset! a non-Atom"),
+            _ => type_error("attempt to reset! a non-Atom"),
         }
     }
 
@@ -156,16 +839,16 @@ impl MalVal {
                 *a.borrow_mut() = f.apply(fargs)?;
                 Ok(a.borrow().clone())
             }
-            _ => error("attempt to swap! a non-Atom"),
+            _ => type_error("attempt to swap! a non-Atom"),
         }
     }
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => Ok((**meta).clone()),
             Func(_, meta) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
-            _ => error("meta not supported by type"),
+            _ => type_error("meta not supported by type"),
         }
     }
 
@@ -174,11 +857,12 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
+            | Set(_, ref mut meta)
             | Func(_, ref mut meta)
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
             }
-            _ => return error("with-meta not supported by type"),
+            _ => return type_error("with-meta not supported by type"),
         };
         Ok(self.clone())
     }
@@ -190,51 +874,184 @@ impl PartialEq for MalVal {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (Big(ref a), Big(ref b)) => a == b,
+            (Int(ref a), Big(ref b)) | (Big(ref b), Int(ref a)) => **b == BigInt::from(*a),
+            (Ratio(ref a), Ratio(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
+            (Char(ref a), Char(ref b)) => a == b,
+            (Regex(ref a), Regex(ref b)) => a.as_str() == b.as_str(),
             (Str(ref a), Str(ref b)) => a == b,
             (Sym(ref a), Sym(ref b)) => a == b,
-            (List(ref a, _), List(ref b, _))
-            | (Vector(ref a, _), Vector(ref b, _))
-            | (List(ref a, _), Vector(ref b, _))
-            | (Vector(ref a, _), List(ref b, _)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => Rc::ptr_eq(a, b),
+            (List(ref a, _), List(ref b, _)) => a == b,
+            (Vector(ref a, _), Vector(ref b, _)) => a == b,
+            (List(..), Vector(..)) | (Vector(..), List(..)) => {
+                let (a, b) = (self.elems().unwrap(), other.elems().unwrap());
+                a.len() == b.len() && a.eq(b)
+            }
+            (LazySeq(_), List(..))
+            | (LazySeq(_), Vector(..))
+            | (LazySeq(_), LazySeq(_))
+            | (List(..), LazySeq(_))
+            | (Vector(..), LazySeq(_)) => match (self.realize(), other.realize()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            },
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
+            (Reduced(ref a), Reduced(ref b)) => a == b,
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+// Values that are = hash alike: lists and vectors share a hash, as do
+// Ints and Bigs of the same value, and map and set hashes ignore order.
+// NaN is not = to itself, like in Clojure, so Eq is not quite reflexive.
+impl Eq for MalVal {}
+
+// lists, vectors and lazy seqs with equal elements hash alike
+fn hash_seq<'a, H: Hasher>(xs: impl Iterator<Item = &'a MalVal>, state: &mut H) {
+    9.hash(state);
+    xs.for_each(|x| x.hash(state));
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+impl StdHash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => 0.hash(state),
+            Bool(b) => (1, b).hash(state),
+            Int(i) => (2, i).hash(state),
+            Big(b) => match b.to_i64() {
+                Some(i) => (2, i).hash(state),
+                None => (3, b).hash(state),
+            },
+            Ratio(r) => (4, r).hash(state),
+            // 0.0 and -0.0 are equal
+            Float(f) => (5, if *f == 0.0 { 0 } else { f.to_bits() }).hash(state),
+            Str(s) => (6, s).hash(state),
+            Sym(s) => (7, s).hash(state),
+            Keyword(k) => (8, k).hash(state),
+            List(..) | Vector(..) => hash_seq(self.elems().unwrap(), state),
+            Hash(hm, _) => {
+                let mut sum: u64 = 0;
+                for (k, v) in hm.iter() {
+                    let mut h = FnvHasher::default();
+                    (k, v).hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                (10, sum).hash(state)
+            }
+            Func(..) | MalFunc { .. } | Atom(_) => 11.hash(state),
+            Set(s, _) => {
+                let mut sum: u64 = 0;
+                for x in s.iter() {
+                    let mut h = FnvHasher::default();
+                    x.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                (12, sum).hash(state)
+            }
+            Char(c) => (13, c).hash(state),
+            Regex(r) => (14, r.as_str()).hash(state),
+            // hashed like the list it realizes to
+            LazySeq(_) => hash_seq(self.realize().unwrap_or_default().iter(), state),
+            Reduced(v) => (15, v).hash(state),
+        }
+    }
+}
+
+impl Builtin {
+    pub fn call(&self, args: MalArgs) -> MalRet {
+        let n = args.len();
+        if n < self.min || self.max.is_some_and(|max| n > max) {
+            return Err(arity_error(n, self.name));
+        }
+        (self.f)(args)
+    }
+}
+
+// max of None means the builtin is variadic
+pub fn func(
+    name: &'static str,
+    min: usize,
+    max: Option<usize>,
+    f: impl Fn(MalArgs) -> MalRet + 'static,
+) -> MalVal {
+    let f = Rc::new(f);
+    Func(Builtin { name, f, min, max }, Rc::new(Nil))
+}
+
+// Atoms change and functions are never =, so a key holding either could
+// not be found again. Lazy seqs are left out as well: they and atoms are
+// the values with interior mutability, which clippy::mutable_key_type is
+// wary of.
+fn hashable(k: &MalVal) -> bool {
+    match k {
+        Atom(_) | Func(..) | MalFunc { .. } | LazySeq(_) => false,
+        List(..) | Vector(..) => k.elems().unwrap().all(hashable),
+        Hash(hm, _) => hm.iter().all(|(k, v)| hashable(k) && hashable(v)),
+        Set(s, _) => s.iter().all(hashable),
+        _ => true,
+    }
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
+        if !hashable(k) {
+            return type_error(&format!("{} cannot be a hash-map key", k.pr_str(true)));
+        }
+        // an = key already present keeps its original form
+        match hm.get_mut(k) {
+            Some(old) => *old = v.clone(),
+            None => {
+                hm.insert(k.clone(), v.clone());
             }
-            _ => return error("key is not string"),
         }
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+#[allow(clippy::mutable_key_type)]
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(k);
     }
-    Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    Ok(Hash(hm, Rc::new(Nil)))
 }
 
+#[allow(clippy::mutable_key_type)]
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(MalMap::default(), kvs)
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn _conj_set(mut s: MalSet, xs: MalArgs) -> MalRet {
+    for x in xs.iter() {
+        if !hashable(x) {
+            return type_error(&format!("{} cannot be a set element", x.pr_str(true)));
+        }
+        // an = element already present keeps its original form
+        if !s.contains(x) {
+            s.insert(x.clone());
+        }
+    }
+    Ok(Set(s, Rc::new(Nil)))
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn _disj(mut s: MalSet, xs: MalArgs) -> MalRet {
+    for x in xs.iter() {
+        s.remove(x);
+    }
+    Ok(Set(s, Rc::new(Nil)))
+}
+
+pub fn hash_set(xs: MalArgs) -> MalRet {
+    _conj_set(MalSet::default(), xs)
 }
@@ -0,0 +1,573 @@
+// A compiler from analyzed forms to bytecode and the stack machine that
+// runs it, used instead of the tree walking run when stepA is given --vm.
+// Calls between compiled functions push a frame rather than recursing, and
+// tail calls replace the caller's frame.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::env::{env_bind_slots, env_frame, env_get, env_get_slot, env_push, Env};
+use crate::types::MalErr::ErrTyped;
+use crate::types::MalVal::{Bool, MalFunc, Nil, Vector};
+use crate::types::{hash_map, hash_set, lazy_seq, type_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::{catch, define, define_local, define_macro, eval, macroexpand, Catch, FnCode, Node};
+
+// Operands index the chunk's tables, jumps are to absolute offsets
+#[derive(Clone, Copy)]
+enum Op {
+    Const(u32),
+    Get(u32),
+    // depth, slot and the symbol, for the error if it is not bound yet
+    Local(u16, u16, u32),
+    // a Local that, if its slot is not bound yet, runs the op after it
+    // instead
+    Late(u16, u16, u32),
+    Def(u32),
+    DefLocal(u16, u16, u32),
+    DefMacro(u32),
+    Pop,
+    Jump(u32),
+    JumpIfFalse(u32),
+    Vector(u32),
+    Hash(u32),
+    Set(u32),
+    // let* binds in a new env, which EndLet leaves
+    Let,
+    Bind,
+    EndLet,
+    // rebind the loop* env to that many values, once its let*s are left
+    Recur(u32),
+    Closure(u32),
+    Macroexpand(u32),
+    Try(u32),
+    LazySeq(u32),
+    Eval,
+    Call(u32),
+    TailCall(u32),
+    Return,
+    Throw(u32),
+}
+
+#[derive(Default)]
+pub struct Chunk {
+    code: Vec<Op>,
+    // the innermost form read by read-file each op was compiled from
+    pos: Vec<Option<Rc<str>>>,
+    consts: Vec<MalVal>,
+    fns: Vec<Rc<Proto>>,
+    lazy_seqs: Vec<Rc<Chunk>>,
+    tries: Vec<TryCode>,
+    errors: Vec<MalErr>,
+}
+
+// A fn* form, its body compiled on the first call
+struct Proto {
+    code: Rc<FnCode>,
+    chunk: RefCell<Option<Rc<Chunk>>>,
+}
+
+impl Proto {
+    fn chunk(&self, env: &Env) -> Result<Rc<Chunk>, MalErr> {
+        if let Some(chunk) = &*self.chunk.borrow() {
+            return Ok(chunk.clone());
+        }
+        let chunk = compile(&*self.code.node(env)?);
+        *self.chunk.borrow_mut() = Some(chunk.clone());
+        Ok(chunk)
+    }
+}
+
+// The fn* form and closure env of a function made by the VM
+fn proto(f: &MalVal) -> Option<(Rc<Proto>, Env)> {
+    match f {
+        MalFunc {
+            code: Some(code),
+            env,
+            ..
+        } => Some((code.clone().downcast().ok()?, env.clone())),
+        _ => None,
+    }
+}
+
+struct TryCode {
+    body: Rc<Chunk>,
+    catches: Vec<Catch<Rc<Chunk>>>,
+    finally: Vec<Rc<Chunk>>,
+}
+
+#[derive(Default)]
+struct Compiler {
+    chunk: Chunk,
+    // positions of the forms being compiled, innermost last
+    pos: Vec<Rc<str>>,
+    // let* and loop* envs the code being compiled runs in
+    lets: usize,
+    // where the body of each enclosing loop* starts, and the lets it is in
+    loops: Vec<(u32, usize)>,
+}
+
+pub fn compile(node: &Node) -> Rc<Chunk> {
+    let mut c = Compiler::default();
+    c.compile(node, true);
+    Rc::new(c.chunk)
+}
+
+fn index<T>(table: &mut Vec<T>, x: T) -> u32 {
+    table.push(x);
+    (table.len() - 1) as u32
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.pos.push(self.pos.last().cloned());
+        self.chunk.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.chunk.code.len() as u32
+    }
+
+    fn constant(&mut self, v: &MalVal) -> u32 {
+        index(&mut self.chunk.consts, v.clone())
+    }
+
+    // Leave the value just pushed as the chunk's, in tail position
+    fn ret(&mut self, tail: bool) {
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    // Open the env of a let* or loop* and bind its inits in it
+    fn bind(&mut self, inits: &[Node]) {
+        self.emit(Op::Let);
+        self.lets += 1;
+        for e in inits {
+            self.compile(e, false);
+            self.emit(Op::Bind);
+        }
+    }
+
+    // In tail position the env is left by returning
+    fn end_let(&mut self, tail: bool) {
+        self.lets -= 1;
+        if !tail {
+            self.emit(Op::EndLet);
+        }
+    }
+
+    fn compile_all(&mut self, nodes: &[Node]) -> u32 {
+        for n in nodes {
+            self.compile(n, false);
+        }
+        nodes.len() as u32
+    }
+
+    fn compile(&mut self, node: &Node, tail: bool) {
+        match node {
+            Node::Const(v) => {
+                let i = self.constant(v);
+                self.emit(Op::Const(i));
+            }
+            Node::Var(s) => {
+                let i = self.constant(s);
+                self.emit(Op::Get(i));
+            }
+            Node::Local(depth, slot, s) => {
+                let i = self.constant(s);
+                self.emit(Op::Local(*depth as u16, *slot as u16, i));
+            }
+            Node::Late(depth, slot, s, before) => {
+                let i = self.constant(s);
+                self.emit(Op::Late(*depth as u16, *slot as u16, i));
+                self.compile(before, false);
+            }
+            Node::Vector(xs) => {
+                let n = self.compile_all(xs);
+                self.emit(Op::Vector(n));
+            }
+            Node::Hash(kvs) => {
+                let n = self.compile_all(kvs);
+                self.emit(Op::Hash(n));
+            }
+            Node::Set(xs) => {
+                let n = self.compile_all(xs);
+                self.emit(Op::Set(n));
+            }
+            Node::Def(a1, a2) => {
+                self.compile(a2, false);
+                let i = self.constant(a1);
+                self.emit(Op::Def(i));
+            }
+            Node::DefLocal(depth, slot, a1, a2) => {
+                self.compile(a2, false);
+                let i = self.constant(a1);
+                self.emit(Op::DefLocal(*depth as u16, *slot as u16, i));
+            }
+            Node::DefMacro(a1, a2) => {
+                self.compile(a2, false);
+                let i = self.constant(a1);
+                self.emit(Op::DefMacro(i));
+            }
+            Node::Let(inits, body) => {
+                self.bind(inits);
+                self.compile(body, tail);
+                self.end_let(tail);
+                return;
+            }
+            Node::Loop(inits, body) => {
+                self.bind(inits);
+                self.loops.push((self.here(), self.lets));
+                self.compile(body, tail);
+                self.loops.pop();
+                self.end_let(tail);
+                return;
+            }
+            // check_recur has made sure there is a loop* to go back to, so
+            // a recur without one is a bug here rather than in the program
+            Node::Recur(args) => {
+                let n = self.compile_all(args);
+                let (start, lets) = match self.loops.last() {
+                    Some(&l) => l,
+                    None => {
+                        let e = ErrTyped("internal", "recur compiled outside loop*".to_string());
+                        let i = index(&mut self.chunk.errors, e);
+                        self.emit(Op::Throw(i));
+                        return;
+                    }
+                };
+                for _ in lets..self.lets {
+                    self.emit(Op::EndLet);
+                }
+                self.emit(Op::Recur(n));
+                self.emit(Op::Jump(start));
+                return;
+            }
+            Node::Do(nodes) => match nodes.split_last() {
+                Some((last, init)) => {
+                    for n in init {
+                        self.compile(n, false);
+                        self.emit(Op::Pop);
+                    }
+                    return self.compile(last, tail);
+                }
+                None => {
+                    let i = self.constant(&Nil);
+                    self.emit(Op::Const(i));
+                }
+            },
+            Node::If(test, then, otherwise) => {
+                self.compile(test, false);
+                let jump_else = self.emit(Op::JumpIfFalse(0));
+                self.compile(then, tail);
+                let jump_end = if tail {
+                    None
+                } else {
+                    Some(self.emit(Op::Jump(0)))
+                };
+                self.chunk.code[jump_else] = Op::JumpIfFalse(self.here());
+                self.compile(otherwise, tail);
+                if let Some(j) = jump_end {
+                    self.chunk.code[j] = Op::Jump(self.here());
+                }
+                return;
+            }
+            Node::Fn(code) => {
+                let proto = Rc::new(Proto {
+                    code: code.clone(),
+                    chunk: RefCell::new(None),
+                });
+                let i = index(&mut self.chunk.fns, proto);
+                self.emit(Op::Closure(i));
+            }
+            Node::Macroexpand(a1) => {
+                let i = self.constant(a1);
+                self.emit(Op::Macroexpand(i));
+            }
+            Node::Try(expr, catches, finally) => {
+                let t = TryCode {
+                    body: compile(expr),
+                    catches: catches
+                        .iter()
+                        .map(|(sel, body)| (sel.as_ref().map(compile), compile(body)))
+                        .collect(),
+                    finally: finally.iter().map(compile).collect(),
+                };
+                let i = index(&mut self.chunk.tries, t);
+                self.emit(Op::Try(i));
+            }
+            Node::LazySeq(body) => {
+                let i = index(&mut self.chunk.lazy_seqs, compile(body));
+                self.emit(Op::LazySeq(i));
+            }
+            Node::Eval(a1) => {
+                self.compile(a1, false);
+                self.emit(Op::Eval);
+            }
+            Node::Call(f, args) => {
+                self.compile(f, false);
+                let n = self.compile_all(args);
+                self.emit(if tail { Op::TailCall(n) } else { Op::Call(n) });
+                return;
+            }
+            Node::Pos(pos, node) => {
+                self.pos.push(pos.clone());
+                self.compile(node, tail);
+                self.pos.pop();
+                return;
+            }
+            Node::Throw(e) => {
+                let i = index(&mut self.chunk.errors, e.clone());
+                self.emit(Op::Throw(i));
+                return;
+            }
+        }
+        self.ret(tail)
+    }
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    env: Env,
+    // envs of the let*s being evaluated, innermost last
+    saved: Vec<Env>,
+    // where the frame's part of the stack starts
+    base: usize,
+    // function whose body is running, else Nil for the chunk run was given
+    func: MalVal,
+    // position of the tail call that first replaced the chunk run was given
+    caller: Option<Rc<str>>,
+}
+
+impl Frame {
+    fn new(chunk: Rc<Chunk>, env: Env, base: usize, func: MalVal) -> Frame {
+        Frame {
+            chunk,
+            ip: 0,
+            env,
+            saved: vec![],
+            base,
+            func,
+            caller: None,
+        }
+    }
+
+    // where the op last run came from
+    fn pos(&self) -> Option<Rc<str>> {
+        self.chunk.pos[self.ip - 1].clone()
+    }
+}
+
+// Run chunk in env. Errors leaving it record the functions whose frames
+// they unwound, for the stack trace.
+pub fn run(chunk: &Rc<Chunk>, env: &Env) -> MalRet {
+    let mut frames = vec![Frame::new(chunk.clone(), env.clone(), 0, Nil)];
+    let mut stack = vec![];
+    exec(&mut frames, &mut stack).map_err(|e| {
+        frames.iter().rev().fold(e, |e, f| {
+            e.unwind(&f.func, f.pos().map(|p| p.to_string()))
+                .unwind(&Nil, f.caller.as_ref().map(|p| p.to_string()))
+        })
+    })
+}
+
+// What MalVal::apply runs for the functions made by the VM, in an env
+// binding their params
+fn run_body(f: MalVal, env: Env) -> MalRet {
+    match proto(&f) {
+        Some((p, _)) => run(&p.chunk(&env)?, &env),
+        None => type_error("attempt to call non-function"),
+    }
+}
+
+fn pop(stack: &mut Vec<MalVal>) -> MalVal {
+    stack.pop().unwrap_or(Nil)
+}
+
+fn pop_n(stack: &mut Vec<MalVal>, n: u32) -> MalArgs {
+    stack.split_off(stack.len() - n as usize)
+}
+
+// Pop the top frame, handing its value to the frame below, or giving it
+// back when that was the last one
+fn ret(frames: &mut Vec<Frame>, stack: &mut Vec<MalVal>) -> Option<MalVal> {
+    let v = pop(stack);
+    if let Some(frame) = frames.pop() {
+        stack.truncate(frame.base);
+    }
+    if frames.is_empty() {
+        return Some(v);
+    }
+    stack.push(v);
+    None
+}
+
+fn run_try(t: &TryCode, env: &Env) -> MalRet {
+    let res = match run(&t.body, env).and_then(MalVal::force_first) {
+        Err(e) => catch(e, &t.catches, env, run),
+        res => res,
+    };
+    for c in &t.finally {
+        run(c, env)?;
+    }
+    res
+}
+
+fn exec(frames: &mut Vec<Frame>, stack: &mut Vec<MalVal>) -> MalRet {
+    loop {
+        let frame = match frames.last_mut() {
+            Some(frame) => frame,
+            None => return Ok(Nil),
+        };
+        let op = frame.chunk.code[frame.ip];
+        frame.ip += 1;
+        match op {
+            Op::Const(i) => stack.push(frame.chunk.consts[i as usize].clone()),
+            Op::Get(i) => stack.push(env_get(&frame.env, &frame.chunk.consts[i as usize])?),
+            Op::Local(depth, slot, i) => stack.push(env_get_slot(
+                &frame.env,
+                depth as usize,
+                slot as usize,
+                &frame.chunk.consts[i as usize],
+            )?),
+            Op::Late(depth, slot, i) => {
+                let key = &frame.chunk.consts[i as usize];
+                if let Ok(v) = env_get_slot(&frame.env, depth as usize, slot as usize, key) {
+                    stack.push(v);
+                    frame.ip += 1;
+                }
+            }
+            Op::Def(i) => {
+                let v = pop(stack);
+                stack.push(define(&frame.env, &frame.chunk.consts[i as usize], v)?);
+            }
+            Op::DefLocal(depth, slot, i) => {
+                let v = pop(stack);
+                stack.push(define_local(
+                    &frame.env,
+                    depth as usize,
+                    slot as usize,
+                    &frame.chunk.consts[i as usize],
+                    v,
+                )?);
+            }
+            Op::DefMacro(i) => {
+                let v = pop(stack);
+                stack.push(define_macro(
+                    &frame.env,
+                    &frame.chunk.consts[i as usize],
+                    v,
+                )?);
+            }
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Jump(to) => frame.ip = to as usize,
+            Op::JumpIfFalse(to) => {
+                if let Bool(false) | Nil = pop(stack) {
+                    frame.ip = to as usize;
+                }
+            }
+            Op::Vector(n) => {
+                let xs = pop_n(stack, n);
+                stack.push(vector!(xs));
+            }
+            Op::Hash(n) => {
+                let kvs = pop_n(stack, n);
+                stack.push(hash_map(kvs)?);
+            }
+            Op::Set(n) => {
+                let xs = pop_n(stack, n);
+                stack.push(hash_set(xs)?);
+            }
+            Op::Let => {
+                let env = env_frame(Some(frame.env.clone()), vec![]);
+                frame.saved.push(std::mem::replace(&mut frame.env, env));
+            }
+            Op::Bind => {
+                let v = pop(stack);
+                env_push(&frame.env, v);
+            }
+            Op::EndLet => {
+                if let Some(env) = frame.saved.pop() {
+                    frame.env = env;
+                }
+            }
+            Op::Recur(n) => {
+                let vals = pop_n(stack, n);
+                frame.env = env_frame(frame.env.outer.clone(), vals);
+            }
+            Op::Closure(i) => {
+                let p = frame.chunk.fns[i as usize].clone();
+                stack.push(MalFunc {
+                    eval: run_body,
+                    ast: p.code.body.clone(),
+                    env: frame.env.clone(),
+                    params: p.code.params.clone(),
+                    is_macro: false,
+                    name: None,
+                    meta: Rc::new(Nil),
+                    code: Some(p),
+                });
+            }
+            Op::Macroexpand(i) => {
+                let form = frame.chunk.consts[i as usize].clone();
+                stack.push(macroexpand(form, &frame.env).1?);
+            }
+            Op::Try(i) => stack.push(run_try(&frame.chunk.tries[i as usize], &frame.env)?),
+            Op::LazySeq(i) => {
+                let (body, env) = (frame.chunk.lazy_seqs[i as usize].clone(), frame.env.clone());
+                stack.push(lazy_seq(move || run(&body, &env)));
+            }
+            Op::Eval => {
+                let ast = pop(stack);
+                let mut env = frame.env.clone();
+                while let Some(ref e) = env.clone().outer {
+                    env = e.clone();
+                }
+                stack.push(eval(ast, env)?);
+            }
+            Op::Call(n) | Op::TailCall(n) => {
+                let tail = matches!(op, Op::TailCall(_));
+                let args = pop_n(stack, n);
+                let f = pop(stack);
+                let (p, fenv) = match proto(&f) {
+                    Some(p) => p,
+                    None => {
+                        stack.push(f.apply(args)?);
+                        if tail {
+                            if let Some(v) = ret(frames, stack) {
+                                return Ok(v);
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let env = env_bind_slots(Some(fenv), &p.code.params, args, f.fn_name())?;
+                let chunk = p.chunk(&env)?;
+                if tail {
+                    if let Nil = frame.func {
+                        frame.caller = frame.pos();
+                    }
+                    stack.truncate(frame.base);
+                    frame.chunk = chunk;
+                    frame.ip = 0;
+                    frame.env = env;
+                    frame.saved.clear();
+                    frame.func = f;
+                } else {
+                    let base = stack.len();
+                    frames.push(Frame::new(chunk, env, base, f));
+                }
+            }
+            Op::Return => {
+                if let Some(v) = ret(frames, stack) {
+                    return Ok(v);
+                }
+            }
+            Op::Throw(i) => return Err(frame.chunk.errors[i as usize].clone()),
+        }
+    }
+}